@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+// Which pressure controller a shot or a profile runs with. Kept apart from the controllers so
+// profiles can carry it and still be read on the host.
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum PressureControllerType {
+    #[default]
+    Heuristic,
+    Pid(PidConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PidConfig {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    // How much of the pump model feed-forward is added to the pid output, 0.0 disables it.
+    pub feed_forward_gain: f32,
+    // Flow (ml/s) the feed-forward expects through the puck at the setpoint when there is no
+    // flow meter to measure it.
+    pub feed_forward_flow: f32,
+    // Max change of the internal setpoint in bar per second, 0.0 disables the rate limit.
+    pub max_setpoint_rate: f32,
+}
+
+impl Default for PidConfig {
+    fn default() -> Self {
+        PidConfig {
+            kp: 0.12,
+            ki: 0.05,
+            kd: 0.01,
+            feed_forward_gain: 1.0,
+            feed_forward_flow: 2.0,
+            max_setpoint_rate: 3.0,
+        }
+    }
+}
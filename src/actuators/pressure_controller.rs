@@ -0,0 +1,139 @@
+use crate::actuators::controller_config::{PidConfig, PressureControllerType};
+use crate::actuators::pump::{
+    get_clicks_per_second_for_flow, get_max_pump_pct, get_pump_pct, MAX_PUMP_CLICKS_PER_SECOND,
+};
+use crate::functional::espresso_state::EspressoStateSnapshot;
+
+// Common interface for the strategies that turn a target pressure into a pump power.
+// The returned value is a fraction of the pump range, 0.0 is off and 1.0 is full on.
+pub trait PressureController {
+    fn get_pump_pct(
+        &mut self,
+        target_pressure: &f32,
+        flow_restriction: &f32,
+        current_state: &EspressoStateSnapshot,
+    ) -> f32;
+
+    // Clears any state carried between calls, called at the start of every shot.
+    fn reset(&mut self) {}
+}
+
+impl PressureControllerType {
+    pub fn build(&self) -> Box<dyn PressureController + Send> {
+        match self {
+            PressureControllerType::Heuristic => Box::new(HeuristicController),
+            PressureControllerType::Pid(config) => Box::new(PidController::new(config.clone())),
+        }
+    }
+}
+
+// The piecewise curve ported from gaggiuino, kept as the default strategy.
+pub struct HeuristicController;
+
+impl PressureController for HeuristicController {
+    fn get_pump_pct(
+        &mut self,
+        target_pressure: &f32,
+        flow_restriction: &f32,
+        current_state: &EspressoStateSnapshot,
+    ) -> f32 {
+        get_pump_pct(target_pressure, flow_restriction, current_state)
+    }
+}
+
+pub struct PidController {
+    config: PidConfig,
+    integral: f32,
+    setpoint: Option<f32>,
+    last_pressure: Option<f32>,
+}
+
+impl PidController {
+    pub fn new(config: PidConfig) -> PidController {
+        PidController {
+            config,
+            integral: 0.0,
+            setpoint: None,
+            last_pressure: None,
+        }
+    }
+
+    fn limit_setpoint(&mut self, target_pressure: f32, current_pressure: f32, dt: f32) -> f32 {
+        // The first setpoint starts from the measured pressure so the ramp begins where the puck is.
+        let previous = self
+            .setpoint
+            .unwrap_or(current_pressure.min(target_pressure));
+        let setpoint = if self.config.max_setpoint_rate <= 0.0 {
+            target_pressure
+        } else {
+            let max_step = self.config.max_setpoint_rate * dt;
+            previous + (target_pressure - previous).clamp(-max_step, max_step)
+        };
+        self.setpoint = Some(setpoint);
+        setpoint
+    }
+}
+
+impl PressureController for PidController {
+    fn get_pump_pct(
+        &mut self,
+        target_pressure: &f32,
+        flow_restriction: &f32,
+        current_state: &EspressoStateSnapshot,
+    ) -> f32 {
+        if target_pressure == &0.0 {
+            self.reset();
+            return 0.0;
+        }
+
+        let dt = current_state.elapsed_time_from_last_read.as_secs_f32();
+        let pressure = current_state.pressure;
+        let setpoint = self.limit_setpoint(*target_pressure, pressure, dt);
+        let error = setpoint - pressure;
+
+        // Derivative on the measurement so setpoint ramps don't kick the pump.
+        let derivative = match self.last_pressure {
+            Some(last_pressure) if dt > 0.0 => -(pressure - last_pressure) / dt,
+            _ => 0.0,
+        };
+        self.last_pressure = Some(pressure);
+
+        // Pump power that would push the expected puck flow at the setpoint. Not the pump
+        // flow: that is the last output, feeding it back would make the P term integrate.
+        let expected_flow = if current_state.espresso_flow > 0.0 {
+            current_state.espresso_flow
+        } else {
+            self.config.feed_forward_flow
+        };
+        let feed_forward = self.config.feed_forward_gain
+            * get_clicks_per_second_for_flow(&expected_flow, &setpoint)
+            / MAX_PUMP_CLICKS_PER_SECOND as f32;
+
+        let max_pump_pct = get_max_pump_pct(flow_restriction, &pressure);
+        let candidate_integral = self.integral + error * dt;
+        let unclamped = feed_forward
+            + self.config.kp * error
+            + self.config.ki * candidate_integral
+            + self.config.kd * derivative;
+
+        // Anti-windup: only keep integrating when the pump is not saturated,
+        // or when the error is pulling the output back out of saturation.
+        let saturated_high = unclamped > max_pump_pct && error > 0.0;
+        let saturated_low = unclamped < 0.0 && error < 0.0;
+        if !saturated_high && !saturated_low {
+            self.integral = candidate_integral;
+        }
+
+        let output = feed_forward
+            + self.config.kp * error
+            + self.config.ki * self.integral
+            + self.config.kd * derivative;
+        output.clamp(0.0, max_pump_pct)
+    }
+
+    fn reset(&mut self) {
+        self.integral = 0.0;
+        self.setpoint = None;
+        self.last_pressure = None;
+    }
+}
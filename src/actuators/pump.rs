@@ -1,3 +1,9 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::actuators::pressure_controller::{HeuristicController, PressureController};
+use crate::actuators::pump_protection::check_pump_request;
+use crate::actuators::watchdog;
+use crate::coffee_machine::water::record_pump_power;
 use crate::functional::espresso_state::EspressoStateSnapshot;
 
 pub const PRESSURE_INEFFICIENCY_COEFFICIENT: [f32; 7] =
@@ -8,6 +14,9 @@ pub static MAX_PUMP_CLICKS_PER_SECOND: i32 = 50;
 pub static FPC_MULTIPLIER: f32 = 1.2;
 pub static PUMP_RANGE: u8 = 100;
 
+// Last raw value the pump was set to.
+static PUMP_POWER: AtomicU8 = AtomicU8::new(0);
+
 struct PumpState {
     clicks: u16,
 }
@@ -34,7 +43,16 @@ pub fn pump_init(power_line_frequency: i32, pump_flow_at_zero: f32) -> PumpConfi
     return PumpConfig::default();
 }
 
-fn get_pump_pct(
+pub fn get_max_pump_pct(flow_restriction: &f32, pressure: &f32) -> f32 {
+    if flow_restriction <= &0.0 {
+        1.0
+    } else {
+        get_clicks_per_second_for_flow(flow_restriction, pressure)
+            / MAX_PUMP_CLICKS_PER_SECOND as f32
+    }
+}
+
+pub fn get_pump_pct(
     target_pressure: &f32,
     flow_restriction: &f32,
     current_state: &EspressoStateSnapshot,
@@ -44,12 +62,7 @@ fn get_pump_pct(
     }
 
     let diff = target_pressure - current_state.pressure;
-    let max_pump_pct = if flow_restriction <= &0.0 {
-        1.0
-    } else {
-        get_clicks_per_second_for_flow(flow_restriction, &current_state.pressure)
-            / MAX_PUMP_CLICKS_PER_SECOND as f32
-    };
+    let max_pump_pct = get_max_pump_pct(flow_restriction, &current_state.pressure);
     let pump_pct_to_maintain_flow =
         get_clicks_per_second_for_flow(&current_state.pump_flow, &current_state.pressure)
             / MAX_PUMP_CLICKS_PER_SECOND as f32;
@@ -74,15 +87,31 @@ pub fn set_pump_pressure(
     flow_restriction: &f32,
     current_state: &EspressoStateSnapshot,
 ) {
-    let pump_pct = get_pump_pct(target_pressure, flow_restriction, current_state);
+    set_pump_pressure_with_controller(
+        &mut HeuristicController,
+        target_pressure,
+        flow_restriction,
+        current_state,
+    );
+}
+
+pub fn set_pump_pressure_with_controller(
+    controller: &mut dyn PressureController,
+    target_pressure: &f32,
+    flow_restriction: &f32,
+    current_state: &EspressoStateSnapshot,
+) {
+    let pump_pct = controller
+        .get_pump_pct(target_pressure, flow_restriction, current_state)
+        .clamp(0.0, 1.0);
     set_pump_to_raw_value((pump_pct * PUMP_RANGE as f32) as u8);
 }
 
-fn set_pump_off() {
+pub fn set_pump_off() {
     pump_set(0);
 }
 
-fn set_pump_full_on() {
+pub fn set_pump_full_on() {
     pump_set(PUMP_RANGE);
 }
 
@@ -90,7 +119,7 @@ fn set_pump_to_raw_value(val: u8) {
     pump_set(val);
 }
 
-fn get_pump_flow_per_click(pressure: &f32) -> f32 {
+pub fn get_pump_flow_per_click(pressure: &f32) -> f32 {
     let fpc = (PRESSURE_INEFFICIENCY_COEFFICIENT[5] / pressure
         + PRESSURE_INEFFICIENCY_COEFFICIENT[6])
         * (-pressure * pressure)
@@ -109,7 +138,13 @@ pub fn get_pump_flow(cps: i32, pressure: &f32) -> f32 {
     (cps as f32) * get_pump_flow_per_click(pressure)
 }
 
-fn get_clicks_per_second_for_flow(flow: &f32, pressure: &f32) -> f32 {
+// Clicks per second the psm lets through at the current pump power. Counting zero crossings
+// (`psm::calculate_cps`) blocks for 100ms, too long for the control loop.
+pub fn get_commanded_cps() -> i32 {
+    PUMP_POWER.load(Ordering::SeqCst) as i32 * MAX_PUMP_CLICKS_PER_SECOND / PUMP_RANGE as i32
+}
+
+pub fn get_clicks_per_second_for_flow(flow: &f32, pressure: &f32) -> f32 {
     if flow == &0.0 {
         return 0.0;
     }
@@ -118,7 +153,7 @@ fn get_clicks_per_second_for_flow(flow: &f32, pressure: &f32) -> f32 {
     cps.min(MAX_PUMP_CLICKS_PER_SECOND as f32)
 }
 
-fn set_pump_flow(
+pub fn set_pump_flow(
     target_flow: &f32,
     pressure_restriction: &f32,
     current_state: &EspressoStateSnapshot,
@@ -134,8 +169,24 @@ fn set_pump_flow(
 
 // Paceholder functions for pump control, watchdog, etc.
 fn pump_set(val: u8) {
-    // Set the pump to the given raw value
+    // Set the pump to the given raw value, unless the protection layer forces it off
+    if watchdog::is_tripped() {
+        log::error!("Actuator watchdog tripped, pump stays off");
+        rbd_dimmer::set_power(0, 0).unwrap();
+        PUMP_POWER.store(0, Ordering::SeqCst);
+        record_pump_power(0.0);
+        return;
+    }
+    let val = match check_pump_request(val) {
+        Ok(val) => val,
+        Err(fault) => {
+            log::error!("Pump protection: {}", fault);
+            0
+        }
+    };
     rbd_dimmer::set_power(0, val).unwrap();
+    PUMP_POWER.store(val, Ordering::SeqCst);
+    record_pump_power(val as f32 / PUMP_RANGE as f32);
 }
 
 // Placeholder for TIM9, assuming a constant value or variable
@@ -0,0 +1,220 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+// Duty tracking of the pump protection, kept apart from the pump so the host tools can run
+// it. Every check takes the current instant.
+
+// A pump switched off for less than this hasn't cooled, the run goes on when it restarts.
+pub const MIN_OFF_GAP: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PumpProtectionConfig {
+    pub max_continuous_run: Duration,
+    pub cool_down: Duration,
+    pub duty_window: Duration,
+    // Max fraction of `duty_window` the pump is allowed to be on.
+    pub max_duty: f32,
+}
+
+impl PumpProtectionConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_continuous_run.is_zero() || self.duty_window.is_zero() {
+            bail!("Pump protection times must be above zero");
+        }
+        if !(self.max_duty > 0.0 && self.max_duty <= 1.0) {
+            bail!("Max pump duty {} is outside (0, 1]", self.max_duty);
+        }
+        Ok(())
+    }
+}
+
+impl Default for PumpProtectionConfig {
+    fn default() -> Self {
+        PumpProtectionConfig {
+            max_continuous_run: Duration::from_secs(120),
+            cool_down: Duration::from_secs(60),
+            duty_window: Duration::from_secs(600),
+            max_duty: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum PumpFault {
+    MaxContinuousRun { ran_for: Duration },
+    DutyCycleExceeded { duty: f32 },
+    CoolingDown { remaining: Duration },
+}
+
+impl fmt::Display for PumpFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PumpFault::MaxContinuousRun { ran_for } => write!(
+                f,
+                "pump stopped after running continuously for {}s",
+                ran_for.as_secs()
+            ),
+            PumpFault::DutyCycleExceeded { duty } => write!(
+                f,
+                "pump stopped, duty cycle {:.0}% over the protection window",
+                duty * 100.0
+            ),
+            PumpFault::CoolingDown { remaining } => {
+                write!(f, "pump cooling down, {}s remaining", remaining.as_secs())
+            }
+        }
+    }
+}
+
+pub struct PumpProtection {
+    config: PumpProtectionConfig,
+    run_started: Option<Instant>,
+    // When the pump was last switched off during the current run.
+    run_paused: Option<Instant>,
+    // Finished runs as (start, end), oldest first. Trimmed to the duty window, with
+    // MIN_OFF_GAP between runs there are at most duty_window / MIN_OFF_GAP of them.
+    runs: VecDeque<(Instant, Instant)>,
+    cooling_until: Option<Instant>,
+    last_fault: Option<PumpFault>,
+}
+
+impl PumpProtection {
+    pub fn new(config: PumpProtectionConfig) -> PumpProtection {
+        PumpProtection {
+            config,
+            run_started: None,
+            run_paused: None,
+            runs: VecDeque::new(),
+            cooling_until: None,
+            last_fault: None,
+        }
+    }
+
+    // Returns the value that is safe to send to the pump, or the fault that forced it off.
+    pub fn check(&mut self, requested: u8, now: Instant) -> Result<u8, PumpFault> {
+        self.trim_runs(now);
+
+        if let Some(cooling_until) = self.cooling_until {
+            if now < cooling_until {
+                self.stop_run(now);
+                return Err(PumpFault::CoolingDown {
+                    remaining: cooling_until - now,
+                });
+            }
+            self.cooling_until = None;
+            self.last_fault = None;
+        }
+
+        if requested == 0 {
+            self.pause_run(now);
+            return Ok(0);
+        }
+
+        // A short stop doesn't cool the pump, e.g. a controller hunting around its target.
+        if let Some(paused) = self.run_paused.take() {
+            if now.duration_since(paused) >= MIN_OFF_GAP {
+                self.end_run(paused);
+            }
+        }
+        let run_started = *self.run_started.get_or_insert(now);
+        let ran_for = now.duration_since(run_started);
+        if ran_for >= self.config.max_continuous_run {
+            return Err(self.trip(PumpFault::MaxContinuousRun { ran_for }, now));
+        }
+
+        let duty = self.duty(now);
+        if duty > self.config.max_duty {
+            return Err(self.trip(PumpFault::DutyCycleExceeded { duty }, now));
+        }
+
+        Ok(requested)
+    }
+
+    pub fn config(&self) -> &PumpProtectionConfig {
+        &self.config
+    }
+
+    // Takes effect from the next check, a running cool down keeps its end.
+    pub fn set_config(&mut self, config: PumpProtectionConfig) {
+        self.config = config;
+    }
+
+    // The fault that stopped the pump, until its cool down has run out.
+    pub fn last_fault(&self, now: Instant) -> Option<PumpFault> {
+        match self.cooling_until {
+            Some(cooling_until) if now < cooling_until => self.last_fault.clone(),
+            _ => None,
+        }
+    }
+
+    fn trip(&mut self, fault: PumpFault, now: Instant) -> PumpFault {
+        self.stop_run(now);
+        self.cooling_until = Some(now + self.config.cool_down);
+        self.last_fault = Some(fault.clone());
+        fault
+    }
+
+    fn stop_run(&mut self, now: Instant) {
+        let end = self.run_paused.take().unwrap_or(now);
+        self.end_run(end);
+    }
+
+    fn pause_run(&mut self, now: Instant) {
+        if self.run_started.is_none() {
+            return;
+        }
+        let paused = *self.run_paused.get_or_insert(now);
+        if now.duration_since(paused) >= MIN_OFF_GAP {
+            self.run_paused = None;
+            self.end_run(paused);
+        }
+    }
+
+    fn end_run(&mut self, end: Instant) {
+        if let Some(run_started) = self.run_started.take() {
+            self.runs.push_back((run_started, end));
+        }
+    }
+
+    #[cfg(test)]
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn trim_runs(&mut self, now: Instant) {
+        while let Some((_, end)) = self.runs.front() {
+            if now.duration_since(*end) > self.config.duty_window {
+                self.runs.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn duty(&self, now: Instant) -> f32 {
+        let window = self.config.duty_window;
+        // Right after boot the window can reach back before the first instant, keep every run then.
+        let clip = |start: Instant| match now.checked_sub(window) {
+            Some(window_start) => start.max(window_start),
+            None => start,
+        };
+        let mut on_time = Duration::ZERO;
+        for (start, end) in self.runs.iter() {
+            let start = clip(*start);
+            if *end > start {
+                on_time += end.duration_since(start);
+            }
+        }
+        if let Some(run_started) = self.run_started {
+            let end = self.run_paused.unwrap_or(now);
+            on_time += end.duration_since(clip(run_started).min(end));
+        }
+        on_time.as_secs_f32() / window.as_secs_f32()
+    }
+}
@@ -0,0 +1,84 @@
+use std::{sync::Mutex, time::Instant};
+
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+
+use crate::{
+    actuators::pump_duty::{PumpFault, PumpProtection, PumpProtectionConfig},
+    functional::handoff::PUMP_FAULTS,
+    storage::nvs,
+};
+
+// Vibratory pumps (ulka and similar) are rated for limited continuous duty.
+// This layer sits between the callers of `pump_set` and the dimmer and refuses
+// to keep the pump running once it has been on for too long. Stops shorter than
+// `MIN_OFF_GAP` don't end a run, see `pump_duty`.
+
+const PUMP_PROTECTION_KEY: &str = "pump_protection";
+
+static PUMP_PROTECTION: OnceCell<Mutex<PumpProtection>> = OnceCell::new();
+
+// Call after `init_nvs`, starts from the saved config.
+pub fn init_pump_protection() {
+    let config = nvs::read_json::<PumpProtectionConfig>(PUMP_PROTECTION_KEY)
+        .filter(|config| match config.validate() {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Ignoring the saved pump protection config: {:?}", e);
+                false
+            }
+        })
+        .unwrap_or_default();
+    PUMP_PROTECTION
+        .set(Mutex::new(PumpProtection::new(config)))
+        .ok();
+}
+
+pub fn get_pump_protection_config() -> PumpProtectionConfig {
+    match PUMP_PROTECTION.get() {
+        Some(protection) => protection
+            .lock()
+            .expect("Failed to acquire lock")
+            .config()
+            .clone(),
+        None => PumpProtectionConfig::default(),
+    }
+}
+
+// Written over ble.
+pub fn set_pump_protection_config(config: PumpProtectionConfig) -> Result<()> {
+    config.validate()?;
+    if let Some(protection) = PUMP_PROTECTION.get() {
+        protection
+            .lock()
+            .expect("Failed to acquire lock")
+            .set_config(config.clone());
+    }
+    nvs::write_json(PUMP_PROTECTION_KEY, &config)
+}
+
+pub fn check_pump_request(requested: u8) -> Result<u8, PumpFault> {
+    let result = match PUMP_PROTECTION.get() {
+        Some(protection) => protection
+            .lock()
+            .expect("Failed to acquire lock")
+            .check(requested, Instant::now()),
+        None => Ok(requested),
+    };
+    // Runs inside `pump_set`, the publisher notifies the app of a new trip.
+    if let Err(fault) = &result {
+        if !matches!(fault, PumpFault::CoolingDown { .. }) {
+            PUMP_FAULTS.publish(fault.clone());
+        }
+    }
+    result
+}
+
+pub fn get_pump_fault() -> Option<PumpFault> {
+    PUMP_PROTECTION.get().and_then(|protection| {
+        protection
+            .lock()
+            .expect("Failed to acquire lock")
+            .last_fault(Instant::now())
+    })
+}
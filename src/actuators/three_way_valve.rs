@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use esp_idf_hal::gpio::{Gpio19, Output, PinDriver};
+use serde::{Deserialize, Serialize};
+
+use crate::actuators::watchdog;
+
+// Closed: coil energised, the exhaust is sealed and the pump feeds the group (brewing).
+// Open: coil off, the group is released to the drip tray. This is also the state the
+// valve falls back to without power, so it is the safe one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ValveState {
+    Open,
+    Closed,
+}
+
+pub static MIN_VALVE_DWELL: Duration = Duration::from_millis(500);
+// Time the group needs to be open to the drip tray before it counts as vented.
+pub static MIN_VENT_TIME: Duration = Duration::from_secs(2);
+// Above this pressure the valve is only allowed to close once the group has vented.
+pub static MAX_CLOSE_PRESSURE: f32 = 0.5;
+
+pub struct ThreeWayValve<'a> {
+    pin: PinDriver<'a, Gpio19, Output>,
+    state: ValveState,
+    last_toggle: Option<Instant>,
+    transitions: u32,
+}
+
+impl<'a> ThreeWayValve<'a> {
+    pub fn new(pin: Gpio19) -> Result<ThreeWayValve<'a>> {
+        let mut pin = PinDriver::output(pin)?;
+        pin.set_low()?;
+        Ok(ThreeWayValve {
+            pin,
+            state: ValveState::Open,
+            last_toggle: None,
+            transitions: 0,
+        })
+    }
+
+    pub fn state(&self) -> ValveState {
+        self.state
+    }
+
+    pub fn transitions(&self) -> u32 {
+        self.transitions
+    }
+
+    pub fn open(&mut self) -> Result<()> {
+        if self.state == ValveState::Open {
+            return Ok(());
+        }
+        self.check_dwell()?;
+        self.pin.set_low()?;
+        self.set_state(ValveState::Open);
+        Ok(())
+    }
+
+    pub fn close(&mut self, pressure: f32) -> Result<()> {
+        // The watchdog vented the group from its interrupt, the valve stays open until a reboot.
+        if watchdog::is_tripped() {
+            self.force_open();
+            bail!("Actuator watchdog tripped, the valve stays open");
+        }
+        if self.state == ValveState::Closed {
+            return Ok(());
+        }
+        self.check_dwell()?;
+        if pressure > MAX_CLOSE_PRESSURE && !self.is_vented() {
+            bail!(
+                "Refusing to close the valve at {:.1}bar before the group has vented",
+                pressure
+            );
+        }
+        self.pin.set_high()?;
+        self.set_state(ValveState::Closed);
+        Ok(())
+    }
+
+    // Opens the valve ignoring the dwell time, for faults and shutdown paths.
+    pub fn force_open(&mut self) {
+        if let Err(e) = self.pin.set_low() {
+            log::error!("Failed to force the valve open: {:?}", e);
+            return;
+        }
+        if self.state != ValveState::Open {
+            self.set_state(ValveState::Open);
+        }
+    }
+
+    fn is_vented(&self) -> bool {
+        match self.last_toggle {
+            Some(last_toggle) => {
+                self.state == ValveState::Open && last_toggle.elapsed() >= MIN_VENT_TIME
+            }
+            None => true,
+        }
+    }
+
+    fn check_dwell(&self) -> Result<()> {
+        if let Some(last_toggle) = self.last_toggle {
+            let elapsed = last_toggle.elapsed();
+            if elapsed < MIN_VALVE_DWELL {
+                bail!(
+                    "Valve toggled {}ms ago, minimum dwell is {}ms",
+                    elapsed.as_millis(),
+                    MIN_VALVE_DWELL.as_millis()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn set_state(&mut self, state: ValveState) {
+        log::info!("Three way valve {:?} -> {:?}", self.state, state);
+        self.state = state;
+        self.last_toggle = Some(Instant::now());
+        self.transitions += 1;
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use esp_idf_hal::timer::{TimerConfig, TimerDriver, TIMER00};
+use serde::Serialize;
+
+// Hardware timer backed watchdog for the actuators. The control loop has to call `feed` every
+// cycle, if it stops doing so (panic, deadlock on a lock...) the timer interrupt forces
+// the heater off and the valve coil off (group vented, see `ThreeWayValve`) straight on the gpio
+// registers, and the pump is switched off from a thread that doesn't depend on the board.
+// Once tripped, `pump_set`, `Board::set_boiler` and `ThreeWayValve::close` refuse to switch
+// anything back on until a reboot.
+
+// gpio numbers of the actuators, see `Board::init`.
+const HEATER_GPIO: i32 = 18;
+const VALVE_GPIO: i32 = 19;
+const PUMP_GPIO: i32 = 23;
+
+const WATCHDOG_RECORD_MAGIC: u32 = 0x5741_5443;
+
+pub static DEFAULT_WATCHDOG_DEADLINE: Duration = Duration::from_secs(2);
+static WATCHDOG_CHECK_PERIOD: Duration = Duration::from_millis(50);
+
+static LAST_FEED_MS: AtomicU32 = AtomicU32::new(0);
+static DEADLINE_MS: AtomicU32 = AtomicU32::new(0);
+static ARMED: AtomicBool = AtomicBool::new(false);
+static TRIPPED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WatchdogRecord {
+    magic: u32,
+    pub trip_count: u32,
+    // Uptime in ms when the last trip happened and when the loop was last fed before it.
+    pub tripped_at_ms: u32,
+    pub last_feed_ms: u32,
+}
+
+// Kept in rtc memory that isn't cleared on a software reset, so the last trip can still be
+// read after the panic/reboot that usually follows a stalled control loop.
+#[link_section = ".rtc_noinit"]
+static mut WATCHDOG_RECORD: WatchdogRecord = WatchdogRecord {
+    magic: 0,
+    trip_count: 0,
+    tripped_at_ms: 0,
+    last_feed_ms: 0,
+};
+
+pub struct ActuatorWatchdog<'a> {
+    _timer: TimerDriver<'a>,
+}
+
+impl<'a> ActuatorWatchdog<'a> {
+    pub fn init(timer: TIMER00, deadline: Duration) -> Result<ActuatorWatchdog<'a>> {
+        DEADLINE_MS.store(deadline.as_millis() as u32, Ordering::SeqCst);
+
+        let mut timer = TimerDriver::new(timer, &TimerConfig::new().auto_reload(true))?;
+        timer.set_alarm(timer.tick_hz() * WATCHDOG_CHECK_PERIOD.as_millis() as u64 / 1000)?;
+        unsafe {
+            timer.subscribe(check_deadline)?;
+        }
+        timer.enable_interrupt()?;
+        timer.enable_alarm(true)?;
+        timer.enable(true)?;
+
+        // rbd_dimmer is not safe to call from the interrupt, so the pump is cut from here.
+        thread::Builder::new()
+            .name(String::from("actuator_watchdog"))
+            .stack_size(4096)
+            .spawn(|| loop {
+                if TRIPPED.load(Ordering::SeqCst) {
+                    if let Err(e) = rbd_dimmer::set_power(0, 0) {
+                        log::error!("Watchdog failed to switch off the pump: {:?}", e);
+                    }
+                }
+                thread::sleep(WATCHDOG_CHECK_PERIOD);
+            })?;
+
+        Ok(ActuatorWatchdog { _timer: timer })
+    }
+}
+
+fn uptime_ms() -> u32 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u32
+}
+
+// Runs in the timer interrupt, only atomics and gpio register writes in here.
+fn check_deadline() {
+    if !ARMED.load(Ordering::SeqCst) || TRIPPED.load(Ordering::SeqCst) {
+        return;
+    }
+    let now = uptime_ms();
+    let last_feed = LAST_FEED_MS.load(Ordering::SeqCst);
+    if now.wrapping_sub(last_feed) < DEADLINE_MS.load(Ordering::SeqCst) {
+        return;
+    }
+
+    TRIPPED.store(true, Ordering::SeqCst);
+    unsafe {
+        esp_idf_sys::gpio_set_level(PUMP_GPIO, 0);
+        esp_idf_sys::gpio_set_level(HEATER_GPIO, 0);
+        esp_idf_sys::gpio_set_level(VALVE_GPIO, 0);
+
+        let record = &mut *std::ptr::addr_of_mut!(WATCHDOG_RECORD);
+        if record.magic != WATCHDOG_RECORD_MAGIC {
+            record.magic = WATCHDOG_RECORD_MAGIC;
+            record.trip_count = 0;
+        }
+        record.trip_count = record.trip_count.wrapping_add(1);
+        record.tripped_at_ms = now;
+        record.last_feed_ms = last_feed;
+    }
+}
+
+// Called by the control loop every cycle. The first call arms the watchdog.
+pub fn feed() {
+    LAST_FEED_MS.store(uptime_ms(), Ordering::SeqCst);
+    ARMED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_tripped() -> bool {
+    TRIPPED.load(Ordering::SeqCst)
+}
+
+pub fn get_watchdog_record() -> Option<WatchdogRecord> {
+    let record = unsafe { *std::ptr::addr_of!(WATCHDOG_RECORD) };
+    if record.magic == WATCHDOG_RECORD_MAGIC {
+        Some(record)
+    } else {
+        None
+    }
+}
@@ -8,7 +8,7 @@ use esp32_nimble::{
 };
 use esp_idf_hal::adc::ADC2;
 use esp_idf_hal::gpio::{
-    AnyInputPin, AnyOutputPin, Gpio12, Gpio17, Gpio18, Gpio19, Gpio2, Gpio20, Gpio21, Gpio22, Gpio25, Input, Output, Pin, PinDriver, Pull
+    AnyInputPin, AnyOutputPin, Gpio12, Gpio17, Gpio18, Gpio2, Gpio20, Gpio21, Gpio22, Gpio25, Gpio27, Input, Output, Pin, PinDriver, Pull
 };
 use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::hal::prelude::Peripherals;
@@ -22,9 +22,12 @@ use std::thread;
 use std::time::Duration;
 
 use crate::actuators::pump::PumpConfig;
+use crate::actuators::three_way_valve::ThreeWayValve;
+use crate::actuators::watchdog::{self, ActuatorWatchdog, DEFAULT_WATCHDOG_DEADLINE};
 use crate::connectivity::bt::ble_server;
+use crate::connectivity::transfer::{self, PREFERRED_MTU};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BoillerState {
     On,
     Off,
@@ -38,16 +41,19 @@ pub struct Board<'a> {
     pub modem: Modem,
     pub adc2: ADC2,
     pub pressure_pin: Gpio12,
+    // Potentiometer of the paddle mode, also on ADC2.
+    pub paddle_pin: Gpio27,
     pub button_state: bool,
     pub pump: Gpio17,
     pub boiller: PinDriver<'a, Gpio18, Output>,
-    pub three_way_valve: Gpio19,
+    pub three_way_valve: ThreeWayValve<'a>,
     pub temperature_sensor_one: Gpio20,
     pub temperature_sensor_two: Gpio21,
     pub temperature_sensor_three: Gpio22,
     pub boiller_state: BoillerState,
     button: PinDriver<'a, Gpio25, Input>,
     pub pump_config: PumpConfig,
+    pub actuator_watchdog: ActuatorWatchdog<'a>,
     pub ble_device: &'a mut BLEDevice, // pub bluetooth: BLEClient,
     pub ble_services: HashMap<String, Arc<mutex::Mutex<BLEService>>>,
     pub ble_characteristics: HashMap<String, Arc<mutex::Mutex<BLECharacteristic>>>,
@@ -63,10 +69,11 @@ impl<'a> Board<'a> {
         let adc2 = p.adc2;
         let modem = p.modem;
         let pressure_pin = p.pins.gpio12;
+        let paddle_pin = p.pins.gpio27;
         let button_pin = p.pins.gpio25;
         let pump = p.pins.gpio17;
         let boiller_pin = p.pins.gpio18;
-        let three_way_valve = p.pins.gpio19;
+        let three_way_valve = ThreeWayValve::new(p.pins.gpio19)?;
         let temperature_sensor_one = p.pins.gpio20;
         let temperature_sensor_two = p.pins.gpio21;
         let temperature_sensor_three = p.pins.gpio22;
@@ -84,6 +91,7 @@ impl<'a> Board<'a> {
         let zc_pin = p.pins.gpio33;
         let d0_pin = p.pins.gpio23;
         let pump_config = PumpConfig::default();
+        let actuator_watchdog = ActuatorWatchdog::init(p.timer00, DEFAULT_WATCHDOG_DEADLINE)?;
 
         // Create the zero-crossing pin and control pin
         let zc = unsafe { AnyInputPin::new(zc_pin.pin()) };
@@ -96,6 +104,7 @@ impl<'a> Board<'a> {
         Ok(Board {
             adc2,
             pressure_pin,
+            paddle_pin,
             modem,
             button_state,
             button,
@@ -107,6 +116,7 @@ impl<'a> Board<'a> {
             temperature_sensor_two,
             temperature_sensor_three,
             pump_config,
+            actuator_watchdog,
             ble_device,
             ble_services,
             ble_characteristics,
@@ -116,21 +126,23 @@ impl<'a> Board<'a> {
     pub fn get_button_state(&self) -> bool {
         return self.button.is_high();
     }
-    // TODO figure why the boiler pin is failign
-    // pub fn set_boiler(&mut self, on_off: BoillerState) -> Result<(), EspError> {
-    //     match on_off {
-    //         BoillerState::On => {
-    //             let set_high = self.boiller.set_high();
-    //             self.boiller_state = BoillerState::On;
-    //             return set_high;
-    //         }
-    //         BoillerState::Off => {
-    //             self.boiller.is_set_low();
-    //             self.boiller_state = BoillerState::Off;
-    //             return Ok(());
-    //         }
-    //     }
-    // }
+    pub fn set_boiler(&mut self, on_off: BoillerState) -> Result<(), Error> {
+        // The watchdog drove the heater pin low from its interrupt, it stays off until a reboot.
+        if watchdog::is_tripped() {
+            self.boiller.set_low()?;
+            self.boiller_state = BoillerState::Off;
+            if on_off == BoillerState::On {
+                anyhow::bail!("Actuator watchdog tripped, the heater stays off");
+            }
+            return Ok(());
+        }
+        match on_off {
+            BoillerState::On => self.boiller.set_high()?,
+            BoillerState::Off => self.boiller.set_low()?,
+        }
+        self.boiller_state = on_off;
+        Ok(())
+    }
 
     pub fn set_ble_service(
         &mut self,
@@ -179,6 +191,14 @@ impl<'a> Board<'a> {
         // Configure Advertiser Data
         new_characteristic
     }
+    pub fn notify_characteristic(&self, characteristic_name: &str, value: &[u8]) {
+        match self.ble_characteristics.get(characteristic_name) {
+            Some(characteristic) => {
+                characteristic.lock().set_value(value).notify();
+            }
+            None => log::warn!("BLE characteristic {} is not set up", characteristic_name),
+        }
+    }
 }
 
 impl<'a> fmt::Debug for Board<'a> {
@@ -211,6 +231,10 @@ fn setup_ble_server(
 ) -> &'static mut BLEDevice {
     // Take ownership of device
     let ble_device = BLEDevice::take();
+    // Larger notifications for the chunked transfers, see `transfer::chunk_size`.
+    if let Err(e) = ble_device.set_preferred_mtu(PREFERRED_MTU) {
+        log::warn!("Failed to set the preferred mtu: {:?}", e);
+    }
 
     // Obtain handle for peripheral advertiser
     let ble_advertiser = ble_device.get_advertising();
@@ -222,6 +246,7 @@ fn setup_ble_server(
     server.on_connect(|server, clntdesc| {
         // Print connected client data
         println!("{:?}", clntdesc);
+        transfer::set_connection(clntdesc.conn_handle());
         // Update connection parameters
         server
             .update_conn_params(clntdesc.conn_handle(), 24, 48, 0, 60)
@@ -229,7 +254,8 @@ fn setup_ble_server(
     });
 
     // Define server disconnect behaviour
-    server.on_disconnect(|_desc, _reason| {
+    server.on_disconnect(|desc, _reason| {
+        transfer::clear_connection(desc.conn_handle());
         println!("Disconnected, back to advertising");
     });
 
@@ -2,26 +2,38 @@ use std::time::SystemTime;
 
 use anyhow::Result;
 
-use crate::{board::board::Board, sensors::temperature::read_temperature};
+use crate::{
+    actuators::{
+        pump_duty::PumpFault,
+        pump_protection::get_pump_fault,
+        three_way_valve::ValveState,
+        watchdog::{get_watchdog_record, WatchdogRecord},
+    },
+    board::board::Board,
+    sensors::temperature::read_temperature,
+};
 
 use serde::Serialize;
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
 pub enum MachineMode {
     ManualBrew,
     ShotProfiling,
     Steam,
     Descale,
+    HotWater,
 }
 
 #[derive(Debug, Serialize)]
 pub struct MachineSnapshot {
     boiler_temp: f32,
     pump_state: f32,
-    valve_state: bool,
+    pump_fault: Option<PumpFault>,
+    valve_state: ValveState,
     brew_button: bool,
     steam_button: bool,
     steam_button_on_time: Option<SystemTime>,
+    watchdog_record: Option<WatchdogRecord>,
 }
 
 impl MachineSnapshot {
@@ -30,14 +42,15 @@ impl MachineSnapshot {
             boiler_temp: read_temperature()?,
             // TODO set the pump_state reading,
             pump_state: 0.0,
-            // TODO set the  valve state reading,
-            valve_state: false,
+            pump_fault: get_pump_fault(),
+            valve_state: board.three_way_valve.state(),
             // TODO set the  brew state reading,
             brew_button: false,
             // TODO set the  steam state reading,
             steam_button: false,
             // TODO set the  steam button on time reading,
             steam_button_on_time: None,
+            watchdog_record: get_watchdog_record(),
         })
     }
 }
@@ -59,4 +72,47 @@ impl CoffeeMachineConfig {
             machine_snapshot: MachineSnapshot::get_machine_snapshot(board).unwrap(),
         }
     }
+
+    pub fn mode(&self) -> MachineMode {
+        self.mode
+    }
+
+    pub fn set_brew_temp_setpoint(&mut self, brew_temp_setpoint: u8) {
+        self.brew_temp_setpoint = brew_temp_setpoint;
+    }
+
+    pub fn set_mode(&mut self, mode: MachineMode) {
+        log::info!("Machine mode {:?} -> {:?}", self.mode, mode);
+        self.mode = mode;
+    }
+}
+
+pub fn set_machine_mode(mode: MachineMode) {
+    match crate::MACHINE_CONFIG.get() {
+        Some(config) => config
+            .lock()
+            .expect("Failed to acquire lock")
+            .set_mode(mode),
+        None => log::warn!("MACHINE_CONFIG is not initialized"),
+    }
+}
+
+pub fn get_machine_mode() -> MachineMode {
+    match crate::MACHINE_CONFIG.get() {
+        Some(config) => config.lock().expect("Failed to acquire lock").mode(),
+        None => {
+            log::warn!("MACHINE_CONFIG is not initialized");
+            MachineMode::ManualBrew
+        }
+    }
+}
+
+pub fn set_brew_temperature(brew_temp_setpoint: u8) {
+    match crate::MACHINE_CONFIG.get() {
+        Some(config) => config
+            .lock()
+            .expect("Failed to acquire lock")
+            .set_brew_temp_setpoint(brew_temp_setpoint),
+        None => log::warn!("MACHINE_CONFIG is not initialized"),
+    }
 }
@@ -0,0 +1,223 @@
+use std::{
+    sync::Mutex,
+    time::{Instant, SystemTime},
+};
+
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actuators::pump::{get_pump_flow_per_click, MAX_PUMP_CLICKS_PER_SECOND},
+    storage::nvs,
+};
+
+// Tracks the water pumped through the machine from the pump model and estimates the scale
+// left behind from the configured hardness. 1 German degree (dGH) is 10mg CaO per litre,
+// which precipitates as roughly 17.8mg of calcium carbonate.
+
+const WATER_STATS_KEY: &str = "water";
+const MG_SCALE_PER_LITRE_PER_DGH: f32 = 17.8;
+// Volume pumped before the counters are written to nvs again, to spare the flash.
+const PERSIST_EVERY_ML: f32 = 250.0;
+
+static WATER_TRACKER: OnceCell<Mutex<WaterTracker>> = OnceCell::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WaterStats {
+    pub total_volume_ml: f32,
+    pub volume_since_descale_ml: f32,
+    pub hardness_dgh: f32,
+    pub descale_threshold_mg: f32,
+    pub last_descale: Option<SystemTime>,
+}
+
+impl Default for WaterStats {
+    fn default() -> Self {
+        WaterStats {
+            total_volume_ml: 0.0,
+            volume_since_descale_ml: 0.0,
+            hardness_dgh: 10.0,
+            descale_threshold_mg: 18000.0,
+            last_descale: None,
+        }
+    }
+}
+
+impl WaterStats {
+    pub fn estimated_scale_mg(&self) -> f32 {
+        self.volume_since_descale_ml / 1000.0 * self.hardness_dgh * MG_SCALE_PER_LITRE_PER_DGH
+    }
+
+    pub fn descale_due(&self) -> bool {
+        self.estimated_scale_mg() >= self.descale_threshold_mg
+    }
+}
+
+// What is sent over ble, the stored counters plus the derived values.
+#[derive(Debug, Clone, Serialize)]
+pub struct WaterReport {
+    #[serde(flatten)]
+    pub stats: WaterStats,
+    pub estimated_scale_mg: f32,
+    pub descale_due: bool,
+}
+
+// Settings the tablet can write to the `water_stats` characteristic.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WaterSettings {
+    pub hardness_dgh: Option<f32>,
+    pub descale_threshold_mg: Option<f32>,
+}
+
+struct WaterTracker {
+    stats: WaterStats,
+    unsaved_ml: f32,
+    // Set by a change that isn't pumped water, e.g. the reset after a descale.
+    changed: bool,
+    pump_pct: f32,
+    pump_pct_since: Option<Instant>,
+    pressure: f32,
+    reminder_pending: bool,
+}
+
+impl WaterTracker {
+    fn add_volume(&mut self, volume_ml: f32) {
+        if volume_ml <= 0.0 || !volume_ml.is_finite() {
+            return;
+        }
+        let was_due = self.stats.descale_due();
+        self.stats.total_volume_ml += volume_ml;
+        self.stats.volume_since_descale_ml += volume_ml;
+        self.unsaved_ml += volume_ml;
+        if !was_due && self.stats.descale_due() {
+            log::warn!(
+                "Descale due, estimated scale {:.0}mg",
+                self.stats.estimated_scale_mg()
+            );
+            self.reminder_pending = true;
+        }
+    }
+
+    // After PERSIST_EVERY_ML, or once the pump stops at the end of a shot or program.
+    fn persist_due(&self) -> bool {
+        self.changed
+            || self.unsaved_ml >= PERSIST_EVERY_ML
+            || (self.pump_pct == 0.0 && self.unsaved_ml > 0.0)
+    }
+
+    fn report(&self) -> WaterReport {
+        WaterReport {
+            stats: self.stats.clone(),
+            estimated_scale_mg: self.stats.estimated_scale_mg(),
+            descale_due: self.stats.descale_due(),
+        }
+    }
+}
+
+fn with_tracker<T>(f: impl FnOnce(&mut WaterTracker) -> T) -> Option<T> {
+    WATER_TRACKER
+        .get()
+        .map(|tracker| f(&mut tracker.lock().expect("Failed to acquire lock")))
+}
+
+pub fn init_water_tracking() {
+    let stats = nvs::read_json(WATER_STATS_KEY).unwrap_or_default();
+    WATER_TRACKER
+        .set(Mutex::new(WaterTracker {
+            stats,
+            unsaved_ml: 0.0,
+            changed: false,
+            pump_pct: 0.0,
+            pump_pct_since: None,
+            pressure: 0.0,
+            reminder_pending: false,
+        }))
+        .ok();
+}
+
+// Called on every pump change, integrates the volume pumped at the previous power.
+pub fn record_pump_power(pump_pct: f32) {
+    with_tracker(|tracker| {
+        let now = Instant::now();
+        if let Some(since) = tracker.pump_pct_since {
+            // get_pump_flow_per_click divides by the pressure.
+            let pressure = tracker.pressure.max(0.01);
+            let flow = tracker.pump_pct
+                * MAX_PUMP_CLICKS_PER_SECOND as f32
+                * get_pump_flow_per_click(&pressure);
+            tracker.add_volume(flow * now.duration_since(since).as_secs_f32());
+        }
+        tracker.pump_pct = pump_pct;
+        tracker.pump_pct_since = Some(now);
+    });
+}
+
+// Polled by the publisher: `record_pump_power` runs inside `pump_set` on the control task and
+// never waits on nvs. The write happens outside the lock.
+pub fn persist_water_stats() {
+    let Some((stats, unsaved_ml)) = with_tracker(|tracker| {
+        tracker.persist_due().then(|| {
+            tracker.changed = false;
+            (tracker.stats.clone(), tracker.unsaved_ml)
+        })
+    })
+    .flatten() else {
+        return;
+    };
+    match nvs::write_json(WATER_STATS_KEY, &stats) {
+        Ok(()) => {
+            with_tracker(|tracker| tracker.unsaved_ml = (tracker.unsaved_ml - unsaved_ml).max(0.0));
+        }
+        Err(e) => {
+            with_tracker(|tracker| tracker.changed = true);
+            log::error!("Failed to store water stats: {:?}", e);
+        }
+    }
+}
+
+// Latest measured pressure, used for the flow per click of the pump model.
+pub fn record_pressure(pressure: f32) {
+    with_tracker(|tracker| tracker.pressure = pressure);
+}
+
+pub fn get_water_report() -> Option<WaterReport> {
+    with_tracker(|tracker| tracker.report())
+}
+
+// Returns the report once each time the descale threshold is crossed.
+pub fn take_descale_reminder() -> Option<WaterReport> {
+    with_tracker(|tracker| {
+        if tracker.reminder_pending {
+            tracker.reminder_pending = false;
+            Some(tracker.report())
+        } else {
+            None
+        }
+    })
+    .flatten()
+}
+
+pub fn apply_settings(settings: WaterSettings) -> Result<()> {
+    with_tracker(|tracker| {
+        if let Some(hardness_dgh) = settings.hardness_dgh {
+            tracker.stats.hardness_dgh = hardness_dgh.max(0.0);
+        }
+        if let Some(descale_threshold_mg) = settings.descale_threshold_mg {
+            tracker.stats.descale_threshold_mg = descale_threshold_mg.max(0.0);
+        }
+        tracker.reminder_pending = tracker.stats.descale_due();
+        // Written by the publisher, not under the lock `record_pump_power` waits on.
+        tracker.changed = true;
+    })
+    .ok_or_else(|| anyhow::anyhow!("Water tracking is not initialized"))
+}
+
+pub fn reset_after_descale() {
+    with_tracker(|tracker| {
+        tracker.stats.volume_since_descale_ml = 0.0;
+        tracker.stats.last_descale = Some(SystemTime::now());
+        tracker.reminder_pending = false;
+        tracker.changed = true;
+    });
+}
@@ -0,0 +1,167 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::functional::{
+    backflush::BackflushConfig,
+    descale::DescaleConfig,
+    espresso::InitialisationType,
+    flush::FlushConfig,
+    hot_water::HotWaterConfig,
+    presets::{PresetId, ShotPreset},
+    replay_fit::ReplayConfig,
+    shot_export::ExportFormat,
+};
+use crate::profile_import::upload::ProfileFormat;
+
+// Commands written by the tablet to the `machine_command` characteristic, e.g.
+// {"command": "start_backflush", "config": {"cycles": 5, ...}}.
+// The ble callbacks can't take the board, so they are queued here and picked up by the loops.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum MachineCommand {
+    StartBackflush {
+        #[serde(default)]
+        config: Option<BackflushConfig>,
+    },
+    StartDescale {
+        #[serde(default)]
+        config: Option<DescaleConfig>,
+    },
+    // Without a config the flush reuses the last one sent, like the double press.
+    StartFlush {
+        #[serde(default)]
+        config: Option<FlushConfig>,
+    },
+    // Stopped with `abort` or the button like the other programs.
+    StartHotWater {
+        #[serde(default)]
+        config: Option<HotWaterConfig>,
+    },
+    StartShot,
+    // How the next shot runs: analog, programmed, profiled or with the paddle.
+    SelectMode {
+        mode: InitialisationType,
+    },
+    SelectPreset {
+        id: PresetId,
+    },
+    SavePreset {
+        id: PresetId,
+        preset: ShotPreset,
+    },
+    DeletePreset {
+        id: PresetId,
+    },
+    // Sends a recorded shot on the `shot_export` characteristic. Queued for the publisher, it
+    // runs while a shot does too.
+    ExportShot {
+        id: u32,
+        format: ExportFormat,
+    },
+    // Saves a recorded shot as a custom preset running its replay profile. Queued for the
+    // publisher, which reads the shot and hands the preset to the control task.
+    ReplayShot {
+        id: u32,
+        name: String,
+        #[serde(default)]
+        config: Option<ReplayConfig>,
+    },
+    // Weight in the cup once the last programmed shot stopped dripping, from the tablet's
+    // scale. Refines the drip lag of the predictive stop.
+    SettledWeight {
+        weight: f32,
+    },
+    // Queued by the `profile_upload` characteristic once all the chunks are in.
+    #[serde(skip_deserializing)]
+    ImportProfile {
+        format: ProfileFormat,
+        data: Vec<u8>,
+    },
+    Continue,
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExportRequest {
+    pub id: u32,
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayRequest {
+    pub id: u32,
+    pub name: String,
+    pub config: ReplayConfig,
+}
+
+static PENDING_COMMANDS: OnceCell<Mutex<VecDeque<MachineCommand>>> = OnceCell::new();
+static PENDING_EXPORTS: OnceCell<Mutex<VecDeque<ExportRequest>>> = OnceCell::new();
+static PENDING_REPLAYS: OnceCell<Mutex<VecDeque<ReplayRequest>>> = OnceCell::new();
+
+fn pending_commands() -> &'static Mutex<VecDeque<MachineCommand>> {
+    PENDING_COMMANDS.get_or_init(|| Mutex::new(VecDeque::new()))
+}
+
+fn pending_exports() -> &'static Mutex<VecDeque<ExportRequest>> {
+    PENDING_EXPORTS.get_or_init(|| Mutex::new(VecDeque::new()))
+}
+
+fn pending_replays() -> &'static Mutex<VecDeque<ReplayRequest>> {
+    PENDING_REPLAYS.get_or_init(|| Mutex::new(VecDeque::new()))
+}
+
+pub fn push_command(command: MachineCommand) {
+    match command {
+        MachineCommand::ExportShot { id, format } => pending_exports()
+            .lock()
+            .expect("Failed to acquire lock")
+            .push_back(ExportRequest { id, format }),
+        MachineCommand::ReplayShot { id, name, config } => pending_replays()
+            .lock()
+            .expect("Failed to acquire lock")
+            .push_back(ReplayRequest {
+                id,
+                name,
+                config: config.unwrap_or_default(),
+            }),
+        command => pending_commands()
+            .lock()
+            .expect("Failed to acquire lock")
+            .push_back(command),
+    }
+}
+
+pub fn take_command() -> Option<MachineCommand> {
+    pending_commands()
+        .lock()
+        .expect("Failed to acquire lock")
+        .pop_front()
+}
+
+// Takes the first queued command `wanted` accepts. Programs use it to pick out the commands
+// they act on, the others stay queued in order for the main task.
+pub fn take_command_if(wanted: impl Fn(&MachineCommand) -> bool) -> Option<MachineCommand> {
+    let mut commands = pending_commands().lock().expect("Failed to acquire lock");
+    let position = commands.iter().position(wanted)?;
+    commands.remove(position)
+}
+
+pub fn take_export() -> Option<ExportRequest> {
+    pending_exports()
+        .lock()
+        .expect("Failed to acquire lock")
+        .pop_front()
+}
+
+pub fn take_replay() -> Option<ReplayRequest> {
+    pending_replays()
+        .lock()
+        .expect("Failed to acquire lock")
+        .pop_front()
+}
+
+pub fn parse_command(data: &[u8]) -> Result<MachineCommand, serde_json::Error> {
+    serde_json::from_slice(data)
+}
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, sync::Arc, thread, time::Duration};
+
+use esp32_nimble::{utilities::mutex, BLECharacteristic};
+use serde_json::json;
+
+use crate::{
+    actuators::pump_protection::get_pump_protection_config,
+    board::board::Board,
+    coffee_machine::water::{get_water_report, persist_water_stats, take_descale_reminder},
+    connectivity::{
+        commands::{take_export, take_replay, ExportRequest, ReplayRequest},
+        transfer::send_chunked,
+    },
+    functional::{
+        control_loop::get_control_loop_config,
+        handoff::{
+            LOOP_STATS, PUMP_FAULTS, REPLAY_PRESETS, SHOT_EVENTS, SHOT_RECORDS, SHOT_TIMER,
+            SNAPSHOTS,
+        },
+        presets::{PresetId, StoredPreset},
+        replay::replay_preset,
+        shot_export::export_shot,
+        shot_history::{get_shot, get_shot_ids, save_shot, ShotRecord},
+    },
+    storage::nvs::write_queued,
+};
+
+// Runs on the main task once the control task owns the board. It notifies what the control
+// task handed off, so a slow ble client or log output never holds up a control cycle. The
+// flash writes, the history reads and the chunked exports run here for the same reason.
+
+const PUBLISH_PERIOD: Duration = Duration::from_millis(200);
+
+pub struct Publisher {
+    characteristics: HashMap<String, Arc<mutex::Mutex<BLECharacteristic>>>,
+    published_water_report: String,
+}
+
+impl Publisher {
+    // Takes its own handles on the characteristics, call it after `init_bluetooth`.
+    pub fn new(board: &Board) -> Publisher {
+        Publisher {
+            characteristics: board.ble_characteristics.clone(),
+            published_water_report: String::new(),
+        }
+    }
+
+    fn notify(&self, characteristic_name: &str, value: &[u8]) {
+        match self.characteristics.get(characteristic_name) {
+            Some(characteristic) => {
+                characteristic.lock().set_value(value).notify();
+            }
+            None => log::warn!("BLE characteristic {} is not set up", characteristic_name),
+        }
+    }
+
+    fn save_shot(&self, record: ShotRecord) {
+        match save_shot(&record) {
+            Ok(id) => {
+                log::info!("Shot saved as {}", id);
+                let ids = serde_json::to_string(&get_shot_ids()).unwrap_or_default();
+                self.notify("shot_history", ids.as_bytes());
+            }
+            Err(e) => log::error!("Failed to save shot: {:?}", e),
+        }
+    }
+
+    fn export_shot(&self, ExportRequest { id, format }: ExportRequest) {
+        let record = match get_shot(id) {
+            Ok(record) => record,
+            Err(e) => {
+                log::error!("Failed to read shot {}: {:?}", id, e);
+                return;
+            }
+        };
+        let sent = record.exported().and_then(|shot| {
+            let notify = |chunk: &[u8]| self.notify("shot_export", chunk);
+            let header = |length: usize| {
+                let header = json!({ "id": id, "format": format, "length": length });
+                header.to_string().into_bytes()
+            };
+            send_chunked(notify, header, |out| export_shot(&shot, format, out))
+        });
+        if let Err(e) = sent {
+            log::error!("Failed to send shot {}: {:?}", id, e);
+        }
+    }
+
+    // The preset is saved by the control task, it owns the preset writes.
+    fn replay_shot(&self, ReplayRequest { id, name, config }: ReplayRequest) {
+        match get_shot(id).and_then(|record| replay_preset(&record, &name, &config)) {
+            Ok(preset) => REPLAY_PRESETS.publish(StoredPreset {
+                id: PresetId::Custom(name),
+                preset,
+            }),
+            Err(e) => log::error!("Failed to replay shot {}: {:?}", id, e),
+        }
+    }
+
+    fn publish(&mut self) {
+        if let Some(snapshot) = SNAPSHOTS.take() {
+            log::debug!("{:?}", snapshot);
+            match serde_json::to_string(&snapshot) {
+                Ok(json) => self.notify("snapshot_state", json.as_bytes()),
+                Err(e) => log::error!("Failed to serialize snapshot: {:?}", e),
+            }
+        }
+        if let Some(events) = SHOT_EVENTS.take() {
+            let events = serde_json::to_string(&events).unwrap_or_default();
+            self.notify("shot_events", events.as_bytes());
+        }
+        if let Some(timer) = SHOT_TIMER.take() {
+            let timer = serde_json::to_string(&timer).unwrap_or_default();
+            self.notify("shot_timer", timer.as_bytes());
+        }
+        if let Some(stats) = LOOP_STATS.take() {
+            if stats.overruns > 0 {
+                log::warn!("Control loop overran {} times", stats.overruns);
+            }
+            let control_loop = json!({ "config": get_control_loop_config(), "stats": stats });
+            self.notify("control_loop", control_loop.to_string().as_bytes());
+        }
+
+        if let Some(fault) = PUMP_FAULTS.take() {
+            log::warn!("Pump protection tripped: {}", fault);
+            let protection = json!({ "config": get_pump_protection_config(), "fault": fault });
+            self.notify("pump_protection", protection.to_string().as_bytes());
+        }
+
+        if let Some(record) = SHOT_RECORDS.take() {
+            self.save_shot(record);
+        }
+        if let Some(request) = take_export() {
+            self.export_shot(request);
+        }
+        if let Some(request) = take_replay() {
+            self.replay_shot(request);
+        }
+        write_queued();
+        persist_water_stats();
+        if take_descale_reminder().is_some() {
+            log::warn!("Descale due, notifying the app");
+        }
+        let water_report = serde_json::to_string(&get_water_report()).unwrap_or_default();
+        if water_report != self.published_water_report {
+            self.notify("water_stats", water_report.as_bytes());
+            self.published_water_report = water_report;
+        }
+    }
+
+    pub fn run(mut self) -> ! {
+        loop {
+            self.publish();
+            thread::sleep(PUBLISH_PERIOD);
+        }
+    }
+}
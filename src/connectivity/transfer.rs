@@ -0,0 +1,167 @@
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use esp_idf_sys::ble_att_mtu;
+
+use crate::storage::flash_ring::{crc32_update, CRC32_INIT};
+
+// Framing for payloads that don't fit in one ble write or notification. The first byte of
+// every chunk says what the rest is:
+//   BEGIN + a json header, DATA + the next bytes of the payload, END + crc32 of the payload.
+// Uploads from the tablet use the same framing with the format name as the header and no crc.
+pub const TRANSFER_BEGIN: u8 = 0x01;
+pub const TRANSFER_DATA: u8 = 0x02;
+pub const TRANSFER_END: u8 = 0x03;
+
+// Asked for on every connection, the client may settle on less.
+pub const PREFERRED_MTU: u16 = 247;
+// Every connection starts with it and keeps it if the client never exchanges mtus.
+const DEFAULT_MTU: u16 = 23;
+// Opcode and attribute handle in front of each notification.
+const NOTIFY_OVERHEAD: usize = 3;
+const NO_CONNECTION: u16 = u16::MAX;
+// A notification that isn't sent by then is taken as a lost client and ends the transfer.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Connection the chunks are sized for, set from the server's connect callbacks.
+static CONNECTION: AtomicU16 = AtomicU16::new(NO_CONNECTION);
+// Set once nimble has sent the last chunk, the next one overwrites the characteristic value.
+static CHUNK_SENT: Mutex<bool> = Mutex::new(false);
+static CHUNK_SENT_CHANGED: Condvar = Condvar::new();
+
+pub fn set_connection(conn_handle: u16) {
+    CONNECTION.store(conn_handle, Ordering::Relaxed);
+}
+
+pub fn clear_connection(conn_handle: u16) {
+    let _ = CONNECTION.compare_exchange(
+        conn_handle,
+        NO_CONNECTION,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+}
+
+// Payload bytes a notification carries on the current connection, after the framing byte.
+fn chunk_size() -> usize {
+    let mtu = match CONNECTION.load(Ordering::Relaxed) {
+        NO_CONNECTION => 0,
+        conn_handle => unsafe { ble_att_mtu(conn_handle) },
+    };
+    mtu.max(DEFAULT_MTU) as usize - NOTIFY_OVERHEAD - 1
+}
+
+// Called from the notify tx callback of the characteristic the chunks are sent on.
+pub fn chunk_sent() {
+    *CHUNK_SENT.lock().expect("Failed to acquire lock") = true;
+    CHUNK_SENT_CHANGED.notify_all();
+}
+
+fn send_chunk(notify: &impl Fn(&[u8]), chunk: &[u8]) -> Result<()> {
+    *CHUNK_SENT.lock().expect("Failed to acquire lock") = false;
+    notify(chunk);
+    let sent = CHUNK_SENT.lock().expect("Failed to acquire lock");
+    let (_, wait) = CHUNK_SENT_CHANGED
+        .wait_timeout_while(sent, CHUNK_TIMEOUT, |sent| !*sent)
+        .expect("Failed to acquire lock");
+    if wait.timed_out() {
+        bail!("Chunk not sent within {:?}", CHUNK_TIMEOUT);
+    }
+    Ok(())
+}
+
+// Counts the bytes of a payload without keeping them.
+#[derive(Default)]
+struct ByteCount(usize);
+
+impl Write for ByteCount {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0 += data.len();
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Fills DATA chunks as the payload is written and sends each one once it is full.
+struct ChunkWriter<F: Fn(&[u8])> {
+    notify: F,
+    chunk: Vec<u8>,
+    chunk_size: usize,
+    crc: u32,
+}
+
+impl<F: Fn(&[u8])> ChunkWriter<F> {
+    fn send(&mut self) -> io::Result<()> {
+        if self.chunk.len() > 1 {
+            send_chunk(&self.notify, &self.chunk).map_err(io::Error::other)?;
+            self.chunk.truncate(1);
+        }
+        Ok(())
+    }
+}
+
+impl<F: Fn(&[u8])> Write for ChunkWriter<F> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.chunk.len() > self.chunk_size {
+            self.send()?;
+        }
+        let length = data.len().min(self.chunk_size + 1 - self.chunk.len());
+        self.chunk.extend_from_slice(&data[..length]);
+        self.crc = crc32_update(self.crc, &data[..length]);
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+// Waits for each chunk to go out before the next, so it runs on the main task and never on
+// the control task. `write` is called twice, once to count the payload for the header and
+// once to send it, so the payload is never held in memory.
+pub fn send_chunked(
+    notify: impl Fn(&[u8]),
+    header: impl FnOnce(usize) -> Vec<u8>,
+    write: impl Fn(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    let chunk_size = chunk_size();
+    let mut length = ByteCount::default();
+    write(&mut length)?;
+    let header = header(length.0);
+    if header.len() > chunk_size {
+        bail!(
+            "Transfer header of {} bytes doesn't fit the {} byte chunks of this connection",
+            header.len(),
+            chunk_size
+        );
+    }
+    let mut chunk = Vec::with_capacity(chunk_size + 1);
+    chunk.push(TRANSFER_BEGIN);
+    chunk.extend_from_slice(&header);
+    send_chunk(&notify, &chunk)?;
+
+    chunk.clear();
+    chunk.push(TRANSFER_DATA);
+    let mut writer = ChunkWriter {
+        notify,
+        chunk,
+        chunk_size,
+        crc: CRC32_INIT,
+    };
+    write(&mut writer)?;
+    writer.flush()?;
+
+    let mut chunk = vec![TRANSFER_END];
+    chunk.extend_from_slice(&(!writer.crc).to_le_bytes());
+    send_chunk(&writer.notify, &chunk)
+}
@@ -0,0 +1,238 @@
+use std::{
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actuators::{
+        pump::{set_pump_full_on, set_pump_off},
+        watchdog,
+    },
+    board::board::Board,
+    connectivity::commands::{take_command_if, MachineCommand},
+    functional::program_stop::ProgramStop,
+    sensors::pressure::read_pressure,
+    storage::nvs,
+};
+
+const CLEANING_STATS_KEY: &str = "cleaning";
+static BACKFLUSH_TICK: Duration = Duration::from_millis(100);
+// How long the program waits for the tank/basket to be rinsed before giving up on the rinse.
+static RINSE_PROMPT_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackflushConfig {
+    pub cycles: u8,
+    pub on_time: Duration,
+    pub off_time: Duration,
+    // Cycles run with clean water once the detergent has been removed, 0 skips the rinse.
+    pub rinse_cycles: u8,
+}
+
+impl Default for BackflushConfig {
+    fn default() -> Self {
+        BackflushConfig {
+            cycles: 5,
+            on_time: Duration::from_secs(10),
+            off_time: Duration::from_secs(10),
+            rinse_cycles: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum BackflushPhase {
+    Cleaning,
+    RinsePrompt,
+    Rinsing,
+    Done,
+    Aborted,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackflushProgress {
+    pub phase: BackflushPhase,
+    pub cycle: u8,
+    pub total_cycles: u8,
+    pub pump_on: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CleaningStats {
+    pub backflush_count: u32,
+    pub last_backflush: Option<SystemTime>,
+}
+
+pub fn get_cleaning_stats() -> CleaningStats {
+    nvs::read_json(CLEANING_STATS_KEY).unwrap_or_default()
+}
+
+fn record_cleaning() -> CleaningStats {
+    let mut stats = get_cleaning_stats();
+    stats.backflush_count += 1;
+    stats.last_backflush = Some(SystemTime::now());
+    if let Err(e) = nvs::queue_json(CLEANING_STATS_KEY, &stats) {
+        log::error!("Failed to store cleaning stats: {:?}", e);
+    }
+    stats
+}
+
+fn notify_progress(board: &Board, progress: &BackflushProgress) {
+    match serde_json::to_string(progress) {
+        Ok(json) => board.notify_characteristic("cleaning_progress", json.as_bytes()),
+        Err(e) => log::error!("Failed to serialize backflush progress: {:?}", e),
+    }
+}
+
+// Waits for `duration` keeping the watchdog fed, returns false if the user aborted.
+fn wait(board: &mut Board, stop: &mut ProgramStop, duration: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        watchdog::feed();
+        if stop.check(board).is_some() {
+            return false;
+        }
+        thread::sleep(BACKFLUSH_TICK);
+    }
+    true
+}
+
+fn run_cycles(
+    board: &mut Board,
+    config: &BackflushConfig,
+    phase: BackflushPhase,
+    total_cycles: u8,
+    stop: &mut ProgramStop,
+) -> Result<bool> {
+    for cycle in 1..=total_cycles {
+        let pressure = read_pressure(board).unwrap_or(0.0);
+        board.three_way_valve.close(pressure)?;
+        set_pump_full_on();
+        notify_progress(
+            board,
+            &BackflushProgress {
+                phase,
+                cycle,
+                total_cycles,
+                pump_on: true,
+            },
+        );
+        let completed = wait(board, stop, config.on_time);
+
+        // Release: the pressure built against the blind basket vents through the valve.
+        set_pump_off();
+        board.three_way_valve.force_open();
+        if !completed {
+            return Ok(false);
+        }
+        notify_progress(
+            board,
+            &BackflushProgress {
+                phase,
+                cycle,
+                total_cycles,
+                pump_on: false,
+            },
+        );
+        if !wait(board, stop, config.off_time) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn wait_for_rinse(board: &Board, config: &BackflushConfig) -> bool {
+    notify_progress(
+        board,
+        &BackflushProgress {
+            phase: BackflushPhase::RinsePrompt,
+            cycle: 0,
+            total_cycles: config.rinse_cycles,
+            pump_on: false,
+        },
+    );
+    let start = Instant::now();
+    while start.elapsed() < RINSE_PROMPT_TIMEOUT {
+        watchdog::feed();
+        if board.get_button_state() {
+            return false;
+        }
+        let prompt_command = |command: &MachineCommand| {
+            matches!(command, MachineCommand::Continue | MachineCommand::Abort)
+        };
+        match take_command_if(prompt_command) {
+            Some(MachineCommand::Continue) => return true,
+            Some(MachineCommand::Abort) => return false,
+            _ => {}
+        }
+        thread::sleep(BACKFLUSH_TICK);
+    }
+    log::info!("Rinse prompt timed out, skipping the rinse");
+    false
+}
+
+// Runs the detergent cycles, then prompts to remove the detergent and runs the rinse cycles.
+// The cleaning is counted once the detergent cycles are done, even if the rinse is skipped.
+pub fn do_backflush(board: &mut Board, config: &BackflushConfig) -> Result<CleaningStats> {
+    log::info!("Starting backflush {:?}", config);
+    let mut stop = ProgramStop::new("backflush");
+    let completed = run_cycles(
+        board,
+        config,
+        BackflushPhase::Cleaning,
+        config.cycles,
+        &mut stop,
+    );
+    let completed = match completed {
+        Ok(completed) => completed,
+        Err(e) => {
+            set_pump_off();
+            board.three_way_valve.force_open();
+            return Err(e);
+        }
+    };
+    if !completed {
+        log::info!("Backflush aborted");
+        notify_progress(
+            board,
+            &BackflushProgress {
+                phase: BackflushPhase::Aborted,
+                cycle: 0,
+                total_cycles: config.cycles,
+                pump_on: false,
+            },
+        );
+        return Ok(get_cleaning_stats());
+    }
+
+    let stats = record_cleaning();
+
+    if config.rinse_cycles > 0 && wait_for_rinse(board, config) {
+        let rinsed = run_cycles(
+            board,
+            config,
+            BackflushPhase::Rinsing,
+            config.rinse_cycles,
+            &mut stop,
+        );
+        if let Err(e) = &rinsed {
+            log::error!("Backflush rinse failed: {:?}", e);
+        }
+        set_pump_off();
+        board.three_way_valve.force_open();
+    }
+
+    notify_progress(
+        board,
+        &BackflushProgress {
+            phase: BackflushPhase::Done,
+            cycle: config.cycles,
+            total_cycles: config.cycles,
+            pump_on: false,
+        },
+    );
+    Ok(stats)
+}
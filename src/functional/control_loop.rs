@@ -0,0 +1,185 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use esp_idf_hal::{cpu::Core, task::thread::ThreadSpawnConfiguration};
+use serde::{Deserialize, Serialize};
+
+use crate::{functional::handoff::LOOP_STATS, storage::nvs};
+
+// The brewing control task: it owns the board (sensors and actuators) and runs the machine
+// loop at a fixed rate on the second core, away from the nimble host on core 0. Everything
+// else only sees what it hands off through `functional::handoff`.
+
+const CONTROL_LOOP_KEY: &str = "control_loop";
+pub const MIN_CONTROL_RATE_HZ: u32 = 10;
+pub const MAX_CONTROL_RATE_HZ: u32 = 100;
+const DEFAULT_CONTROL_RATE_HZ: u32 = 50;
+
+const CONTROL_TASK_NAME: &[u8] = b"brew_control\0";
+const CONTROL_TASK_STACK: usize = 48 * 1024;
+// Above the main task and the ble publisher, below the nimble host.
+const CONTROL_TASK_PRIORITY: u8 = 10;
+const CONTROL_TASK_CORE: Core = Core::Core1;
+
+// Stats are handed off once per second while a loop runs.
+const STATS_PERIOD: Duration = Duration::from_secs(1);
+
+static CONTROL_RATE_HZ: AtomicU32 = AtomicU32::new(DEFAULT_CONTROL_RATE_HZ);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlLoopConfig {
+    pub rate_hz: u32,
+}
+
+impl Default for ControlLoopConfig {
+    fn default() -> Self {
+        ControlLoopConfig {
+            rate_hz: DEFAULT_CONTROL_RATE_HZ,
+        }
+    }
+}
+
+pub fn init_control_loop() {
+    let config: ControlLoopConfig = nvs::read_json(CONTROL_LOOP_KEY).unwrap_or_default();
+    if let Err(e) = apply_config(&config) {
+        log::error!("Ignoring the saved control loop config: {:?}", e);
+    }
+}
+
+fn apply_config(config: &ControlLoopConfig) -> Result<()> {
+    if !(MIN_CONTROL_RATE_HZ..=MAX_CONTROL_RATE_HZ).contains(&config.rate_hz) {
+        bail!(
+            "Control rate {}Hz is outside {}..={}Hz",
+            config.rate_hz,
+            MIN_CONTROL_RATE_HZ,
+            MAX_CONTROL_RATE_HZ
+        );
+    }
+    CONTROL_RATE_HZ.store(config.rate_hz, Ordering::SeqCst);
+    Ok(())
+}
+
+// Written over ble, the new rate is used from the next loop started (the next shot).
+pub fn set_control_loop_config(config: ControlLoopConfig) -> Result<()> {
+    apply_config(&config)?;
+    nvs::write_json(CONTROL_LOOP_KEY, &config)
+}
+
+pub fn get_control_loop_config() -> ControlLoopConfig {
+    ControlLoopConfig {
+        rate_hz: CONTROL_RATE_HZ.load(Ordering::SeqCst),
+    }
+}
+
+pub fn control_period() -> Duration {
+    Duration::from_micros(1_000_000 / CONTROL_RATE_HZ.load(Ordering::SeqCst) as u64)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct LoopStats {
+    pub period_us: u32,
+    pub cycles: u32,
+    // Cycles whose work ran past the next tick, the loop skips ahead instead of bursting.
+    pub overruns: u32,
+    // How late the loop woke up after its tick.
+    pub max_jitter_us: u32,
+    pub mean_jitter_us: u32,
+    // Time spent in a cycle before waiting for the next tick.
+    pub max_busy_us: u32,
+}
+
+pub struct ControlLoop {
+    period: Duration,
+    next_tick: Instant,
+    cycle_start: Instant,
+    last_stats: Instant,
+    jitter_sum_us: u64,
+    stats: LoopStats,
+}
+
+impl ControlLoop {
+    // Starts ticking at the configured rate, the first tick is one period from now.
+    pub fn start() -> ControlLoop {
+        let period = control_period();
+        let now = Instant::now();
+        ControlLoop {
+            period,
+            next_tick: now + period,
+            cycle_start: now,
+            last_stats: now,
+            jitter_sum_us: 0,
+            stats: LoopStats {
+                period_us: period.as_micros() as u32,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn stats(&self) -> LoopStats {
+        self.stats
+    }
+
+    // Ends the cycle: waits for the next tick and measures how late it woke up.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        let busy = now - self.cycle_start;
+        self.stats.max_busy_us = self.stats.max_busy_us.max(busy.as_micros() as u32);
+        self.stats.cycles += 1;
+
+        if now >= self.next_tick {
+            self.stats.overruns += 1;
+            self.next_tick = now;
+        } else {
+            thread::sleep(self.next_tick - now);
+        }
+        let woke = Instant::now();
+        let jitter_us = (woke - self.next_tick).as_micros() as u32;
+        self.stats.max_jitter_us = self.stats.max_jitter_us.max(jitter_us);
+        self.jitter_sum_us += jitter_us as u64;
+        self.stats.mean_jitter_us = (self.jitter_sum_us / self.stats.cycles as u64) as u32;
+
+        self.cycle_start = woke;
+        self.next_tick += self.period;
+        if woke - self.last_stats >= STATS_PERIOD {
+            self.last_stats = woke;
+            LOOP_STATS.publish(self.stats);
+        }
+    }
+
+    // Hands off the final stats of the loop, called when a shot ends.
+    pub fn finish(self) -> LoopStats {
+        log::info!("Control loop stats {:?}", self.stats);
+        LOOP_STATS.publish(self.stats);
+        self.stats
+    }
+}
+
+// Runs `task` on its own FreeRTOS task pinned to the control core, with a priority above
+// the ble and logging work so a busy notification doesn't delay a control cycle.
+pub fn spawn_control_task<F>(task: F) -> Result<JoinHandle<()>>
+where
+    F: FnOnce() + Send + 'static,
+{
+    ThreadSpawnConfiguration {
+        name: Some(CONTROL_TASK_NAME),
+        stack_size: CONTROL_TASK_STACK,
+        priority: CONTROL_TASK_PRIORITY,
+        pin_to_core: Some(CONTROL_TASK_CORE),
+        ..Default::default()
+    }
+    .set()?;
+    let handle = thread::Builder::new()
+        .stack_size(CONTROL_TASK_STACK)
+        .spawn(task);
+    // Threads spawned after this one go back to the defaults.
+    ThreadSpawnConfiguration::default().set()?;
+    Ok(handle?)
+}
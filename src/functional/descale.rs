@@ -0,0 +1,325 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actuators::{
+        pump::{set_pump_full_on, set_pump_off},
+        watchdog,
+    },
+    board::board::{Board, BoillerState},
+    coffee_machine::{
+        config::{get_machine_mode, set_machine_mode, MachineMode},
+        water::reset_after_descale,
+    },
+    connectivity::commands::{take_command_if, MachineCommand},
+    functional::program_stop::ProgramStop,
+    sensors::{pressure::read_pressure, temperature::read_temperature},
+    storage::nvs,
+};
+
+const DESCALE_PROGRESS_KEY: &str = "descale";
+static DESCALE_TICK: Duration = Duration::from_millis(100);
+// How long a prompt waits for the user before the descale is aborted, the heater is capped
+// and the machine left in descale mode while it waits.
+static PROMPT_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DescaleConfig {
+    // Number of pump/soak rounds with the descaling solution.
+    pub cycles: u8,
+    pub group_pump_time: Duration,
+    pub steam_path_pump_time: Duration,
+    pub soak_time: Duration,
+    pub rinse_cycles: u8,
+    pub rinse_pump_time: Duration,
+    // The heater is switched off above this temperature while descaling.
+    pub max_temperature: f32,
+}
+
+impl Default for DescaleConfig {
+    fn default() -> Self {
+        DescaleConfig {
+            cycles: 4,
+            group_pump_time: Duration::from_secs(15),
+            steam_path_pump_time: Duration::from_secs(15),
+            soak_time: Duration::from_secs(300),
+            rinse_cycles: 3,
+            rinse_pump_time: Duration::from_secs(30),
+            max_temperature: 60.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DescalePrompt {
+    // Fill the tank with descaling solution.
+    FillSolution,
+    // Open the steam/hot water knob so the solution goes through the boiler path.
+    OpenSteamValve,
+    CloseSteamValve,
+    // Empty and rinse the tank, then fill it with fresh water.
+    RinseTank,
+    // Shown after a power loss before continuing where the program stopped.
+    Resume,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DescaleStep {
+    Prompt(DescalePrompt),
+    PumpGroup(Duration),
+    PumpSteamPath(Duration),
+    Soak(Duration),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DescaleProgress {
+    pub config: DescaleConfig,
+    pub step: usize,
+    pub total_steps: usize,
+    pub current: Option<DescaleStep>,
+    pub done: bool,
+    pub aborted: bool,
+}
+
+pub fn descale_steps(config: &DescaleConfig) -> Vec<DescaleStep> {
+    let mut steps = vec![DescaleStep::Prompt(DescalePrompt::FillSolution)];
+    for _ in 0..config.cycles {
+        steps.push(DescaleStep::PumpGroup(config.group_pump_time));
+        steps.push(DescaleStep::Prompt(DescalePrompt::OpenSteamValve));
+        steps.push(DescaleStep::PumpSteamPath(config.steam_path_pump_time));
+        steps.push(DescaleStep::Prompt(DescalePrompt::CloseSteamValve));
+        steps.push(DescaleStep::Soak(config.soak_time));
+    }
+    steps.push(DescaleStep::Prompt(DescalePrompt::RinseTank));
+    for _ in 0..config.rinse_cycles {
+        steps.push(DescaleStep::PumpGroup(config.rinse_pump_time));
+        steps.push(DescaleStep::Prompt(DescalePrompt::OpenSteamValve));
+        steps.push(DescaleStep::PumpSteamPath(config.rinse_pump_time));
+        steps.push(DescaleStep::Prompt(DescalePrompt::CloseSteamValve));
+    }
+    steps
+}
+
+// Progress of a descale that was interrupted by a power loss, if any.
+pub fn get_saved_progress() -> Option<DescaleProgress> {
+    nvs::read_json::<DescaleProgress>(DESCALE_PROGRESS_KEY).filter(|progress| !progress.done)
+}
+
+fn save_progress(progress: &DescaleProgress) {
+    if let Err(e) = nvs::queue_json(DESCALE_PROGRESS_KEY, progress) {
+        log::error!("Failed to store descale progress: {:?}", e);
+    }
+}
+
+fn clear_progress() {
+    nvs::queue_remove(DESCALE_PROGRESS_KEY);
+}
+
+fn notify_progress(board: &Board, progress: &DescaleProgress) {
+    match serde_json::to_string(progress) {
+        Ok(json) => board.notify_characteristic("descale_progress", json.as_bytes()),
+        Err(e) => log::error!("Failed to serialize descale progress: {:?}", e),
+    }
+}
+
+// Bang-bang cap on the heater, the solution shouldn't get anywhere near brew temperature.
+fn cap_heater(board: &mut Board, max_temperature: f32) {
+    let temperature = match read_temperature() {
+        Ok(temperature) => temperature,
+        Err(e) => {
+            log::error!("Failed to read temperature while descaling: {:?}", e);
+            max_temperature
+        }
+    };
+    let state = if temperature >= max_temperature {
+        BoillerState::Off
+    } else {
+        BoillerState::On
+    };
+    if board.boiller_state != state {
+        if let Err(e) = board.set_boiler(state) {
+            log::error!("Failed to set boiler while descaling: {:?}", e);
+        }
+    }
+}
+
+// Waits for `duration` keeping the heater capped, returns false if the user aborted.
+fn wait(
+    board: &mut Board,
+    config: &DescaleConfig,
+    stop: &mut ProgramStop,
+    duration: Duration,
+) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        watchdog::feed();
+        cap_heater(board, config.max_temperature);
+        if stop.check(board).is_some() {
+            return false;
+        }
+        thread::sleep(DESCALE_TICK);
+    }
+    true
+}
+
+fn wait_for_continue(board: &mut Board, config: &DescaleConfig) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < PROMPT_TIMEOUT {
+        watchdog::feed();
+        cap_heater(board, config.max_temperature);
+        if board.get_button_state() {
+            return false;
+        }
+        let prompt_command = |command: &MachineCommand| {
+            matches!(command, MachineCommand::Continue | MachineCommand::Abort)
+        };
+        match take_command_if(prompt_command) {
+            Some(MachineCommand::Continue) => return true,
+            Some(MachineCommand::Abort) => return false,
+            _ => {}
+        }
+        thread::sleep(DESCALE_TICK);
+    }
+    log::info!("Descale prompt timed out");
+    false
+}
+
+fn pump_for(
+    board: &mut Board,
+    config: &DescaleConfig,
+    stop: &mut ProgramStop,
+    duration: Duration,
+    through_group: bool,
+) -> Result<bool> {
+    // Closed valve sends the water to the group, open lets it go through the boiler
+    // and out of the steam wand.
+    if through_group {
+        let pressure = read_pressure(board).unwrap_or(0.0);
+        board.three_way_valve.close(pressure)?;
+    }
+    set_pump_full_on();
+    let completed = wait(board, config, stop, duration);
+    set_pump_off();
+    board.three_way_valve.force_open();
+    Ok(completed)
+}
+
+fn run_step(
+    board: &mut Board,
+    config: &DescaleConfig,
+    stop: &mut ProgramStop,
+    step: DescaleStep,
+) -> Result<bool> {
+    match step {
+        DescaleStep::Prompt(_) => Ok(wait_for_continue(board, config)),
+        DescaleStep::PumpGroup(duration) => pump_for(board, config, stop, duration, true),
+        DescaleStep::PumpSteamPath(duration) => pump_for(board, config, stop, duration, false),
+        DescaleStep::Soak(duration) => Ok(wait(board, config, stop, duration)),
+    }
+}
+
+// Runs the descale program from `progress.step`. Every step is queued for nvs before it
+// starts and the publisher stores it within a publish period, so after a power loss the
+// program restarts the interrupted step.
+fn do_descale(board: &mut Board, mut progress: DescaleProgress) -> Result<DescaleProgress> {
+    let steps = descale_steps(&progress.config);
+    progress.total_steps = steps.len();
+
+    let mut stop = ProgramStop::new("descale");
+    let mut result = Ok(());
+    while progress.step < steps.len() {
+        let step = steps[progress.step];
+        progress.current = Some(step);
+        save_progress(&progress);
+        notify_progress(board, &progress);
+        log::info!(
+            "Descale step {}/{}: {:?}",
+            progress.step + 1,
+            progress.total_steps,
+            step
+        );
+
+        match run_step(board, &progress.config, &mut stop, step) {
+            Ok(true) => progress.step += 1,
+            Ok(false) => {
+                log::info!("Descale aborted");
+                progress.aborted = true;
+                break;
+            }
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+
+    set_pump_off();
+    board.three_way_valve.force_open();
+
+    progress.current = None;
+    progress.done = progress.step >= steps.len();
+    if progress.done {
+        reset_after_descale();
+    }
+    if progress.done || progress.aborted {
+        clear_progress();
+    }
+    notify_progress(board, &progress);
+    result.map(|_| progress)
+}
+
+// The descale caps the heater, the boiler and the mode are put back the way they were before
+// it once it ends, on errors too.
+fn restore_machine(board: &mut Board, boiler_state: BoillerState, machine_mode: MachineMode) {
+    if board.boiller_state != boiler_state {
+        if let Err(e) = board.set_boiler(boiler_state) {
+            log::error!("Failed to restore the boiler state: {:?}", e);
+        }
+    }
+    set_machine_mode(machine_mode);
+}
+
+pub fn start_descale(board: &mut Board, config: DescaleConfig) -> Result<DescaleProgress> {
+    let progress = DescaleProgress {
+        config,
+        step: 0,
+        total_steps: 0,
+        current: None,
+        done: false,
+        aborted: false,
+    };
+    let boiler_state = board.boiller_state;
+    let machine_mode = get_machine_mode();
+    set_machine_mode(MachineMode::Descale);
+    let result = do_descale(board, progress);
+    restore_machine(board, boiler_state, machine_mode);
+    result
+}
+
+// Continues a descale interrupted by a power loss, the user confirms before anything pumps.
+pub fn resume_descale(board: &mut Board, progress: DescaleProgress) -> Result<DescaleProgress> {
+    log::info!("Resuming descale at step {}", progress.step + 1);
+    let boiler_state = board.boiller_state;
+    let machine_mode = get_machine_mode();
+    set_machine_mode(MachineMode::Descale);
+    let mut prompt = progress.clone();
+    prompt.current = Some(DescaleStep::Prompt(DescalePrompt::Resume));
+    notify_progress(board, &prompt);
+    let result = if wait_for_continue(board, &progress.config) {
+        do_descale(board, progress)
+    } else {
+        log::info!("Descale resume declined");
+        clear_progress();
+        Ok(DescaleProgress {
+            aborted: true,
+            ..progress
+        })
+    };
+    restore_machine(board, boiler_state, machine_mode);
+    result
+}
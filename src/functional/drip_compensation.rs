@@ -0,0 +1,83 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+// The drip lag model of the predictive stop: the final weight is predicted as
+// weight + flow * drip lag, and the lag is learned from the settled weight of each shot.
+// Kept apart from the stop itself so the host tools can run it.
+
+pub const MAX_DRIP_LAG: f32 = 5.0;
+// Below this flow at the stop the drip says nothing about the lag.
+const MIN_LEARNING_FLOW: f32 = 0.3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DripCompensation {
+    pub enabled: bool,
+    // Seconds of the flow at the stop that still end up in the cup.
+    pub drip_lag: f32,
+    // How far each shot moves the lag towards what it measured, 0.0 freezes the lag.
+    pub learning_rate: f32,
+    // Time the flow meters are read after the stop to measure the drip.
+    pub settle_time: Duration,
+    pub shots_learned: u32,
+    // Settled weight minus target of the last learned shot.
+    pub last_error: Option<f32>,
+}
+
+impl Default for DripCompensation {
+    fn default() -> Self {
+        DripCompensation {
+            enabled: true,
+            drip_lag: 1.0,
+            learning_rate: 0.3,
+            settle_time: Duration::from_secs(5),
+            shots_learned: 0,
+            last_error: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StopPoint {
+    // When the shot was stopped, the settle time counts from it.
+    pub time: Instant,
+    // Cup weight from the flow meters at the stop, None when only the pump model ran.
+    pub measured_weight: Option<f32>,
+    // Espresso flow from the flow meters at the stop.
+    pub measured_flow: f32,
+    pub target_weight: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LearnResult {
+    Learned,
+    // The pump model counts the water pumped, which is always more than what reaches the
+    // cup: a settled cup weight compared with it would drive the lag to 0.
+    NoMeasuredWeight,
+    LowFlow,
+}
+
+impl DripCompensation {
+    pub fn predict_final_weight(&self, weight: f32, flow: f32) -> f32 {
+        if self.enabled {
+            weight + flow.max(0.0) * self.drip_lag
+        } else {
+            weight
+        }
+    }
+
+    pub fn learn(&mut self, stop: &StopPoint, settled_weight: f32) -> LearnResult {
+        let Some(weight) = stop.measured_weight else {
+            return LearnResult::NoMeasuredWeight;
+        };
+        if stop.measured_flow < MIN_LEARNING_FLOW {
+            return LearnResult::LowFlow;
+        }
+        let measured_lag =
+            ((settled_weight - weight) / stop.measured_flow).clamp(0.0, MAX_DRIP_LAG);
+        self.drip_lag += (measured_lag - self.drip_lag) * self.learning_rate.clamp(0.0, 1.0);
+        self.shots_learned += 1;
+        self.last_error = Some(settled_weight - stop.target_weight);
+        LearnResult::Learned
+    }
+}
@@ -1,14 +1,45 @@
 use crate::{
-    actuators::pump::set_pump_pressure,
+    actuators::{
+        controller_config::PressureControllerType,
+        pump::{set_pump_flow, set_pump_off, set_pump_pressure_with_controller},
+        pump_protection::get_pump_fault,
+        watchdog,
+    },
     board::board::Board,
-    functional::espresso_state::{push_snapshot, EspressoStateSnapshot},
-    BOARD, ESPRESSO_SYSTEM_STACK,
+    connectivity::commands::{take_command_if, MachineCommand},
+    functional::{
+        control_loop::{control_period, ControlLoop},
+        drip_compensation::StopPoint,
+        espresso_state::{clear_snapshots, push_snapshot, EspressoStateSnapshot},
+        handoff::{SHOT_RECORDS, SHOT_TIMER},
+        paddle::{get_ble_target, reset_ble_target, PaddleConfig, PaddleFilter, PaddleSource},
+        predictive_stop::{clear_pending_stop, get_drip_compensation, settle_after_stop},
+        presets::ShotPreset,
+        profile::{ControlType, Profile, ProfileEngine, ProfileInput},
+        puck_resistance::{configure_puck_resistance, PuckResistanceConfig},
+        shot_history::ShotRecorder,
+        shot_series::ShotStage,
+        shot_timer::{ShotTimer, ShotTimerConfig},
+    },
+    sensors::{paddle::read_paddle, pressure::read_pressure},
+    ESPRESSO_SYSTEM_STACK,
 };
+use anyhow::{bail, Result};
 use esp32_nimble::utilities::mutex::MutexGuard;
 use once_cell::sync::OnceCell;
-use std::{sync::Mutex, thread, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+// Safety net for programmed shots without a configured shot time.
+pub static MAX_SHOT_TIME: Duration = Duration::from_secs(90);
+static DEPRESSURISE_TIMEOUT: Duration = Duration::from_secs(3);
+static DEPRESSURISED_PRESSURE: f32 = 0.5;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum EspressoType {
     Double,
     Lungo,
@@ -16,13 +47,85 @@ pub enum EspressoType {
     Ristretto,
 }
 
-#[derive(Debug, PartialEq)]
+impl EspressoType {
+    pub const ALL: [EspressoType; 4] = [
+        EspressoType::Double,
+        EspressoType::Lungo,
+        EspressoType::Single,
+        EspressoType::Ristretto,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum InitialisationType {
     AnalogButton,
     Program,
+    Profile,
+    Paddle,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShotTrigger {
+    Button,
+    Ble,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShotStopReason {
+    TargetWeight,
+    ShotTime,
+    ProfileFinished,
+    Button,
+    Aborted,
+    PumpFault,
+    SensorError,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShotResult {
+    pub start_time: SystemTime,
+    pub duration: Duration,
+    pub target_weight: Option<f32>,
+    pub final_weight: f32,
+    pub peak_pressure: f32,
+    pub stop_reason: ShotStopReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PreinfusionConfig {
+    pub enabled: bool,
+    // Pressure fills at `pressure` limited to `flow`, flow fills the other way around.
+    pub control: ControlType,
+    pub pressure: f32,
+    pub flow: f32,
+    // The fill ends when the pressure reaches the threshold or the first drips hit the cup.
+    // The drips need the flow meters, without them only the pressure and max_time end it.
+    pub pressure_threshold: f32,
+    pub first_drip_weight: f32,
+    pub max_time: Duration,
+    // Soak with the pump off once the puck is saturated, 0 skips the bloom.
+    pub bloom_time: Duration,
+    // Time to ramp from the bloom pressure to the brew pressure.
+    pub ramp_time: Duration,
+}
+
+impl Default for PreinfusionConfig {
+    fn default() -> Self {
+        PreinfusionConfig {
+            enabled: true,
+            control: ControlType::Flow,
+            pressure: 3.0,
+            flow: 4.0,
+            pressure_threshold: 2.5,
+            first_drip_weight: 0.5,
+            max_time: Duration::from_secs(15),
+            bloom_time: Duration::from_secs(5),
+            ramp_time: Duration::from_secs(3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ShotConfig {
     grains_weight_in: f32,
     espresso_yield: Option<f32>,
@@ -30,8 +133,13 @@ pub struct ShotConfig {
     override_shot_time: Option<u32>,
     pressure: f32,
     flow_restriction: f32,
-    // TODO create pressure profile and flow profile for the espresso machine.
-    // pressure_profile
+    pressure_controller: PressureControllerType,
+    profile: Option<Profile>,
+    preinfusion: PreinfusionConfig,
+    puck_resistance: PuckResistanceConfig,
+    // When the timer of an analog shot starts.
+    timer: ShotTimerConfig,
+    paddle: PaddleConfig,
 }
 impl Default for ShotConfig {
     fn default() -> Self {
@@ -42,6 +150,12 @@ impl Default for ShotConfig {
             override_final_weight: None,
             override_shot_time: None,
             flow_restriction: 2.0,
+            pressure_controller: PressureControllerType::Heuristic,
+            profile: None,
+            preinfusion: PreinfusionConfig::default(),
+            puck_resistance: PuckResistanceConfig::default(),
+            timer: ShotTimerConfig::default(),
+            paddle: PaddleConfig::default(),
         }
     }
 }
@@ -61,47 +175,631 @@ pub fn init_espresso_config() {
     ESPRESSO_CONFIG.set(Mutex::new(config)).unwrap();
 }
 static ESPRESSO_CONFIG: OnceCell<Mutex<EspressoConfig>> = OnceCell::new();
+
+// Merges `patch` into `target`, objects are merged key by key and anything else replaces.
+fn merge_json(target: &mut serde_json::Value, patch: serde_json::Value) {
+    match (target, patch) {
+        (serde_json::Value::Object(target), serde_json::Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge_json(target.entry(key).or_insert(serde_json::Value::Null), value);
+            }
+        }
+        (target, patch) => *target = patch,
+    }
+}
+
+// Applies a partial shot configuration written over ble, e.g. {"preinfusion": {"bloom_time": ...}}.
+// The config is locked for the whole shot, so updates are refused while one is running.
+pub fn update_shot_config(data: &[u8]) -> Result<ShotConfig> {
+    let config_lock = match ESPRESSO_CONFIG.get() {
+        Some(config_lock) => config_lock,
+        None => bail!("EspressoConfig is not initialized"),
+    };
+    let mut config = match config_lock.try_lock() {
+        Ok(config) => config,
+        Err(_) => bail!("Can't update the shot config while a shot is running"),
+    };
+    let patch: serde_json::Value = serde_json::from_slice(data)?;
+    let mut shot_config = serde_json::to_value(&config._shot_config)?;
+    merge_json(&mut shot_config, patch);
+    config._shot_config = serde_json::from_value(shot_config)?;
+    Ok(config._shot_config.clone())
+}
+
+// Loads a preset into the active shot config, switching to programmed or profiled shots.
+pub fn apply_preset(preset: &ShotPreset) -> Result<ShotConfig> {
+    let config_lock = match ESPRESSO_CONFIG.get() {
+        Some(config_lock) => config_lock,
+        None => bail!("EspressoConfig is not initialized"),
+    };
+    let mut config = match config_lock.try_lock() {
+        Ok(config) => config,
+        Err(_) => bail!("Can't select a preset while a shot is running"),
+    };
+    let shot_config = &mut config._shot_config;
+    shot_config.grains_weight_in = preset.dose;
+    shot_config.espresso_yield = Some(preset.ratio);
+    shot_config.override_final_weight = None;
+    shot_config.override_shot_time = Some(preset.max_time);
+    shot_config.pressure = preset.pressure;
+    shot_config.profile = preset.profile.clone();
+    let shot_config = shot_config.clone();
+    config.initialisation_type = if shot_config.profile.is_some() {
+        InitialisationType::Profile
+    } else {
+        InitialisationType::Program
+    };
+    Ok(shot_config)
+}
+
+pub fn select_mode(mode: InitialisationType) -> Result<()> {
+    let config_lock = match ESPRESSO_CONFIG.get() {
+        Some(config_lock) => config_lock,
+        None => bail!("EspressoConfig is not initialized"),
+    };
+    let mut config = match config_lock.try_lock() {
+        Ok(config) => config,
+        Err(_) => bail!("Can't change the shot mode while a shot is running"),
+    };
+    if mode == InitialisationType::Profile && config._shot_config.profile.is_none() {
+        bail!("No profile configured");
+    }
+    config.initialisation_type = mode;
+    Ok(())
+}
+
+pub fn get_shot_config() -> Option<ShotConfig> {
+    ESPRESSO_CONFIG
+        .get()
+        .map(|config_lock| config_lock.lock().unwrap()._shot_config.clone())
+}
+
+impl ShotConfig {
+    // Weight to stop at, the override or the dose times the yield ratio.
+    pub fn target_weight(&self) -> Option<f32> {
+        self.override_final_weight.or(self
+            .espresso_yield
+            .map(|ratio| self.grains_weight_in * ratio))
+    }
+
+    pub fn max_shot_time(&self) -> Duration {
+        match self.override_shot_time {
+            Some(seconds) => Duration::from_secs(seconds as u64),
+            None => MAX_SHOT_TIME,
+        }
+    }
+}
+
+// A shot started with the brew switch stops when the switch is turned off, one started
+// over ble stops on an abort command or when the switch is pressed.
+fn check_user_stop(board: &Board, trigger: ShotTrigger) -> Option<ShotStopReason> {
+    match trigger {
+        ShotTrigger::Button if !board.get_button_state() => Some(ShotStopReason::Button),
+        ShotTrigger::Button => None,
+        ShotTrigger::Ble => {
+            if board.get_button_state() {
+                return Some(ShotStopReason::Button);
+            }
+            take_command_if(|command| matches!(command, MachineCommand::Abort))
+                .map(|_| ShotStopReason::Aborted)
+        }
+    }
+}
+
+fn depressurise(board: &mut Board) {
+    set_pump_off();
+    board.three_way_valve.force_open();
+    let start = Instant::now();
+    while start.elapsed() < DEPRESSURISE_TIMEOUT {
+        watchdog::feed();
+        match read_pressure(board) {
+            Ok(pressure) if pressure <= DEPRESSURISED_PRESSURE => break,
+            Ok(_) => {}
+            Err(e) => {
+                println!("failed to read pressure while depressurising: {:?}", e);
+                break;
+            }
+        }
+        thread::sleep(control_period());
+    }
+}
+
+fn next_shot_stage(
+    stage: ShotStage,
+    stage_time: Duration,
+    preinfusion: &PreinfusionConfig,
+    espresso_snapshot: &EspressoStateSnapshot,
+) -> ShotStage {
+    match stage {
+        ShotStage::Preinfusion => {
+            let saturated = espresso_snapshot.pressure >= preinfusion.pressure_threshold
+                || espresso_snapshot
+                    .measured_weight
+                    .is_some_and(|weight| weight >= preinfusion.first_drip_weight)
+                || stage_time >= preinfusion.max_time;
+            if !saturated {
+                ShotStage::Preinfusion
+            } else if preinfusion.bloom_time.is_zero() {
+                ShotStage::Ramp
+            } else {
+                ShotStage::Bloom
+            }
+        }
+        ShotStage::Bloom if stage_time >= preinfusion.bloom_time => ShotStage::Ramp,
+        ShotStage::Ramp if stage_time >= preinfusion.ramp_time => ShotStage::Brew,
+        stage => stage,
+    }
+}
+
+// After a shot that stopped on its own the switch is usually still on, wait for it to be
+// turned off so the main loop doesn't start another shot straight away.
+fn wait_for_button_release(board: &Board) {
+    while board.get_button_state() {
+        watchdog::feed();
+        thread::sleep(control_period());
+    }
+}
+
+fn record_shot(board: &Board, result: &ShotResult, recorder: ShotRecorder) {
+    println!("shot finished {:?}", result);
+    match serde_json::to_string(result) {
+        Ok(json) => board.notify_characteristic("last_shot", json.as_bytes()),
+        Err(e) => println!("failed to serialize shot result: {:?}", e),
+    }
+    let record = recorder.finish(result);
+    for event in &record.events {
+        println!("shot event {:?}", event);
+    }
+    let summary = serde_json::to_string(&record.summary).unwrap_or_default();
+    board.notify_characteristic("shot_summary", summary.as_bytes());
+    let events = serde_json::to_string(&record.events).unwrap_or_default();
+    board.notify_characteristic("shot_events", events.as_bytes());
+    // Saved by the publisher, writing the flash would hold up the control task.
+    SHOT_RECORDS.publish(record);
+}
 pub fn do_analog_espresso(config: &EspressoConfig, board: &mut Board) {
     println!("doing analog espresso");
-    let mut button_state = true;
-    while button_state {
-        button_state = board.get_button_state();
-        if !button_state {
-            break;
-        }
-        println!("Making espresso");
-        // Simulate making espresso
-        println!("thread sleep");
-        let espresso_snapshot = EspressoStateSnapshot::get_state(board).unwrap();
+    let shot_config = &config._shot_config;
+    let mut pressure_controller = shot_config.pressure_controller.build();
+    let pressure = read_pressure(board).unwrap_or(0.0);
+    if let Err(e) = board.three_way_valve.close(pressure) {
+        println!("not starting analog espresso: {:?}", e);
+        return;
+    }
+    clear_snapshots();
+    let mut recorder = ShotRecorder::new(None, Some(shot_config.grains_weight_in));
+    // The shot time and the recording start with the timer, the pump runs from the press.
+    let mut timer = ShotTimer::new(shot_config.timer.clone());
+    let mut control_loop = ControlLoop::start();
+    let mut final_weight = 0.0;
+    let mut peak_pressure: f32 = 0.0;
+    let stop_reason = loop {
+        watchdog::feed();
+        if !board.get_button_state() {
+            break ShotStopReason::Button;
+        }
+        let espresso_snapshot = match EspressoStateSnapshot::get_state(board) {
+            Ok(espresso_snapshot) => espresso_snapshot,
+            Err(e) => {
+                println!("stopping analog espresso, failed to read state: {:?}", e);
+                break ShotStopReason::SensorError;
+            }
+        };
         push_snapshot(espresso_snapshot.clone());
-        println!("get espresso_snapshot {:?}", espresso_snapshot);
-        set_pump_pressure(
-            &config._shot_config.pressure,
-            &config._shot_config.flow_restriction,
+        if let Some(shot_time) = timer.update(&espresso_snapshot) {
+            recorder.record(shot_time, &espresso_snapshot);
+        }
+        SHOT_TIMER.publish(timer.state());
+        final_weight = espresso_snapshot.estimated_weight;
+        peak_pressure = peak_pressure.max(espresso_snapshot.pressure);
+        if let Some(fault) = get_pump_fault() {
+            println!("stopping analog espresso: {}", fault);
+            break ShotStopReason::PumpFault;
+        }
+        set_pump_pressure_with_controller(
+            pressure_controller.as_mut(),
+            &shot_config.pressure,
+            &shot_config.flow_restriction,
+            &espresso_snapshot,
+        );
+        control_loop.wait();
+    };
+    control_loop.finish();
+    set_pump_off();
+    board.three_way_valve.force_open();
+    timer.stop();
+    SHOT_TIMER.publish(timer.state());
+
+    let duration = match timer.elapsed() {
+        Some(duration) => duration,
+        None => {
+            println!("analog espresso stopped before its timer started, not saving it");
+            return;
+        }
+    };
+    record_shot(
+        board,
+        &ShotResult {
+            start_time: SystemTime::now() - duration,
+            duration,
+            target_weight: shot_config.target_weight(),
+            final_weight,
+            peak_pressure,
+            stop_reason,
+        },
+        recorder,
+    );
+}
+
+pub fn do_auto_espresso(config: &EspressoConfig, board: &mut Board, trigger: ShotTrigger) {
+    let shot_config = &config._shot_config;
+    let target_weight = shot_config.target_weight();
+    let max_shot_time = shot_config.max_shot_time();
+    println!(
+        "doing programmed espresso, target weight {:?}, max time {:?}",
+        target_weight, max_shot_time
+    );
+    let mut pressure_controller = shot_config.pressure_controller.build();
+    let pressure = read_pressure(board).unwrap_or(0.0);
+    if let Err(e) = board.three_way_valve.close(pressure) {
+        println!("not starting programmed espresso: {:?}", e);
+        return;
+    }
+    clear_snapshots();
+    clear_pending_stop();
+    let drip_compensation = get_drip_compensation();
+    let mut predicted_stop = None;
+    let mut recorder = ShotRecorder::new(None, Some(shot_config.grains_weight_in));
+
+    let preinfusion = &shot_config.preinfusion;
+    let start_time = SystemTime::now();
+    let shot_start = Instant::now();
+    let mut control_loop = ControlLoop::start();
+    let mut final_weight = 0.0;
+    let mut peak_pressure: f32 = 0.0;
+    let mut stage = if preinfusion.enabled {
+        ShotStage::Preinfusion
+    } else {
+        ShotStage::Brew
+    };
+    let mut stage_start = shot_start;
+    let mut ramp_from = 0.0;
+    let stop_reason = loop {
+        watchdog::feed();
+        if let Some(reason) = check_user_stop(board, trigger) {
+            break reason;
+        }
+        let mut espresso_snapshot = match EspressoStateSnapshot::get_state(board) {
+            Ok(espresso_snapshot) => espresso_snapshot,
+            Err(e) => {
+                println!(
+                    "stopping programmed espresso, failed to read state: {:?}",
+                    e
+                );
+                break ShotStopReason::SensorError;
+            }
+        };
+        let next_stage = next_shot_stage(
+            stage,
+            stage_start.elapsed(),
+            preinfusion,
             &espresso_snapshot,
         );
-        std::thread::sleep(Duration::from_secs(1));
+        if next_stage != stage {
+            println!("shot stage {:?} -> {:?}", stage, next_stage);
+            stage = next_stage;
+            stage_start = Instant::now();
+            ramp_from = espresso_snapshot.pressure;
+        }
+        espresso_snapshot.shot_stage = Some(stage);
+        push_snapshot(espresso_snapshot.clone());
+        recorder.record(shot_start.elapsed(), &espresso_snapshot);
+        final_weight = espresso_snapshot.estimated_weight;
+        peak_pressure = peak_pressure.max(espresso_snapshot.pressure);
+
+        if let Some(fault) = get_pump_fault() {
+            println!("stopping programmed espresso: {}", fault);
+            break ShotStopReason::PumpFault;
+        }
+        if let Some(target_weight) = target_weight {
+            // Stops early by what is still going to drip into the cup.
+            let flow = espresso_snapshot.estimated_espresso_flow;
+            if drip_compensation.predict_final_weight(final_weight, flow) >= target_weight {
+                if drip_compensation.enabled {
+                    predicted_stop = Some(StopPoint {
+                        time: Instant::now(),
+                        measured_weight: espresso_snapshot.measured_weight,
+                        measured_flow: espresso_snapshot.espresso_flow,
+                        target_weight,
+                    });
+                }
+                break ShotStopReason::TargetWeight;
+            }
+        }
+        if shot_start.elapsed() >= max_shot_time {
+            break ShotStopReason::ShotTime;
+        }
+
+        match stage {
+            ShotStage::Preinfusion => match preinfusion.control {
+                ControlType::Pressure => set_pump_pressure_with_controller(
+                    pressure_controller.as_mut(),
+                    &preinfusion.pressure,
+                    &preinfusion.flow,
+                    &espresso_snapshot,
+                ),
+                ControlType::Flow => {
+                    set_pump_flow(&preinfusion.flow, &preinfusion.pressure, &espresso_snapshot)
+                }
+            },
+            ShotStage::Bloom => set_pump_off(),
+            ShotStage::Ramp => {
+                let progress = if preinfusion.ramp_time.is_zero() {
+                    1.0
+                } else {
+                    (stage_start.elapsed().as_secs_f32() / preinfusion.ramp_time.as_secs_f32())
+                        .min(1.0)
+                };
+                let target_pressure = ramp_from + (shot_config.pressure - ramp_from) * progress;
+                set_pump_pressure_with_controller(
+                    pressure_controller.as_mut(),
+                    &target_pressure,
+                    &shot_config.flow_restriction,
+                    &espresso_snapshot,
+                );
+            }
+            ShotStage::Brew => set_pump_pressure_with_controller(
+                pressure_controller.as_mut(),
+                &shot_config.pressure,
+                &shot_config.flow_restriction,
+                &espresso_snapshot,
+            ),
+        }
+        control_loop.wait();
+    };
+    control_loop.finish();
+    let duration = shot_start.elapsed();
+
+    // The drip starts with the stop, before the group is vented.
+    if let Some(stop) = predicted_stop {
+        settle_after_stop(board, stop);
+    }
+    depressurise(board);
+    record_shot(
+        board,
+        &ShotResult {
+            start_time,
+            duration,
+            target_weight,
+            final_weight,
+            peak_pressure,
+            stop_reason,
+        },
+        recorder,
+    );
+    if trigger == ShotTrigger::Button {
+        wait_for_button_release(board);
     }
 }
 
-pub fn do_auto_espresso(config: &EspressoConfig) {
-    // TODO make espresso with the programable things.
+pub fn do_auto_espresso_with_pressure_profile(config: &EspressoConfig, board: &mut Board) {
+    let profile = match &config._shot_config.profile {
+        Some(profile) => profile.clone(),
+        None => {
+            println!("no profile configured");
+            return;
+        }
+    };
+    println!("doing profiled espresso {}", profile.name);
+    let mut pressure_controller = profile
+        .pressure_controller
+        .as_ref()
+        .unwrap_or(&config._shot_config.pressure_controller)
+        .build();
+    let mut engine = ProfileEngine::new(profile);
+    let pressure = read_pressure(board).unwrap_or(0.0);
+    if let Err(e) = board.three_way_valve.close(pressure) {
+        println!("not starting profiled espresso: {:?}", e);
+        return;
+    }
+
+    clear_snapshots();
+    let mut recorder = ShotRecorder::new(
+        Some(engine.profile().name.clone()),
+        Some(config._shot_config.grains_weight_in),
+    );
+
+    let target_weight = config._shot_config.target_weight();
+    let start_time = SystemTime::now();
+    let shot_start = Instant::now();
+    let mut control_loop = ControlLoop::start();
+    let mut final_weight = 0.0;
+    let mut peak_pressure: f32 = 0.0;
+    let stop_reason = loop {
+        watchdog::feed();
+        if !board.get_button_state() {
+            break ShotStopReason::Button;
+        }
+        let mut espresso_snapshot = match EspressoStateSnapshot::get_state(board) {
+            Ok(espresso_snapshot) => espresso_snapshot,
+            Err(e) => {
+                println!("stopping profiled espresso, failed to read state: {:?}", e);
+                break ShotStopReason::SensorError;
+            }
+        };
+        if let Some(fault) = get_pump_fault() {
+            println!("stopping profiled espresso: {}", fault);
+            break ShotStopReason::PumpFault;
+        }
+
+        let target = engine.step(&ProfileInput {
+            shot_time: shot_start.elapsed(),
+            pressure: espresso_snapshot.pressure,
+            flow: espresso_snapshot.pump_flow,
+            weight: espresso_snapshot.estimated_weight,
+            resistance: espresso_snapshot.puck_resistance,
+        });
+        espresso_snapshot.profile_phase = engine.current_phase();
+        push_snapshot(espresso_snapshot.clone());
+        recorder.record(shot_start.elapsed(), &espresso_snapshot);
+        final_weight = espresso_snapshot.estimated_weight;
+        peak_pressure = peak_pressure.max(espresso_snapshot.pressure);
+
+        let target = match target {
+            Some(target) => target,
+            None => {
+                println!("profile finished");
+                break ShotStopReason::ProfileFinished;
+            }
+        };
+        match target.control {
+            ControlType::Pressure => set_pump_pressure_with_controller(
+                pressure_controller.as_mut(),
+                &target.target,
+                &target.restriction,
+                &espresso_snapshot,
+            ),
+            ControlType::Flow => {
+                set_pump_flow(&target.target, &target.restriction, &espresso_snapshot)
+            }
+        }
+        control_loop.wait();
+    };
+    control_loop.finish();
+    depressurise(board);
+    record_shot(
+        board,
+        &ShotResult {
+            start_time,
+            duration: shot_start.elapsed(),
+            target_weight,
+            final_weight,
+            peak_pressure,
+            stop_reason,
+        },
+        recorder,
+    );
+    wait_for_button_release(board);
 }
 
-pub fn do_auto_espresso_with_pressure_profile(config: EspressoConfig) {
-    // TODO make espresso with the programable things.
+// Lever-like shot, the pressure follows the paddle until the switch is released (or the shot
+// is aborted when it was started over ble). The target is recorded so the shot can be
+// replayed as a profile.
+pub fn do_paddle_espresso(config: &EspressoConfig, board: &mut Board, trigger: ShotTrigger) {
+    let shot_config = &config._shot_config;
+    let paddle = &shot_config.paddle;
+    println!("doing paddle espresso from {:?}", paddle.source);
+    let pressure = read_pressure(board).unwrap_or(0.0);
+    if let Err(e) = board.three_way_valve.close(pressure) {
+        println!("not starting paddle espresso: {:?}", e);
+        return;
+    }
+    clear_snapshots();
+    let mut recorder = ShotRecorder::new(None, Some(shot_config.grains_weight_in));
+    let mut filter = PaddleFilter::new(paddle.clone());
+    let mut pressure_controller = shot_config.pressure_controller.build();
+
+    let start_time = SystemTime::now();
+    let shot_start = Instant::now();
+    let mut control_loop = ControlLoop::start();
+    let mut final_weight = 0.0;
+    let mut peak_pressure: f32 = 0.0;
+    let stop_reason = loop {
+        watchdog::feed();
+        if let Some(reason) = check_user_stop(board, trigger) {
+            break reason;
+        }
+        let mut espresso_snapshot = match EspressoStateSnapshot::get_state(board) {
+            Ok(espresso_snapshot) => espresso_snapshot,
+            Err(e) => {
+                println!("stopping paddle espresso, failed to read state: {:?}", e);
+                break ShotStopReason::SensorError;
+            }
+        };
+        let requested = match paddle.source {
+            PaddleSource::Potentiometer => match read_paddle(board) {
+                Ok(position) => paddle.potentiometer_pressure(position),
+                Err(e) => {
+                    println!(
+                        "stopping paddle espresso, failed to read the paddle: {:?}",
+                        e
+                    );
+                    break ShotStopReason::SensorError;
+                }
+            },
+            PaddleSource::Ble => get_ble_target(),
+        };
+        let target_pressure =
+            filter.update(requested, espresso_snapshot.elapsed_time_from_last_read);
+        espresso_snapshot.target_pressure = Some(target_pressure);
+        push_snapshot(espresso_snapshot.clone());
+        recorder.record(shot_start.elapsed(), &espresso_snapshot);
+        final_weight = espresso_snapshot.estimated_weight;
+        peak_pressure = peak_pressure.max(espresso_snapshot.pressure);
+
+        if let Some(fault) = get_pump_fault() {
+            println!("stopping paddle espresso: {}", fault);
+            break ShotStopReason::PumpFault;
+        }
+        set_pump_pressure_with_controller(
+            pressure_controller.as_mut(),
+            &target_pressure,
+            &shot_config.flow_restriction,
+            &espresso_snapshot,
+        );
+        control_loop.wait();
+    };
+    control_loop.finish();
+    depressurise(board);
+    reset_ble_target();
+    record_shot(
+        board,
+        &ShotResult {
+            start_time,
+            duration: shot_start.elapsed(),
+            target_weight: None,
+            final_weight,
+            peak_pressure,
+            stop_reason,
+        },
+        recorder,
+    );
+    // A ble shot stopped with the switch, the press mustn't start another shot.
+    wait_for_button_release(board);
 }
 
 pub fn do_espresso(board: &mut Board) {
     if let Some(config_lock) = ESPRESSO_CONFIG.get() {
         let config = config_lock.lock().unwrap();
+        configure_puck_resistance(&config._shot_config.puck_resistance);
         println!("config {:?}", config);
         match config.initialisation_type {
             InitialisationType::AnalogButton => {
                 println!("doing analog espresso");
                 do_analog_espresso(&config, board)
             }
-            InitialisationType::Program => do_auto_espresso(&config),
+            InitialisationType::Program => do_auto_espresso(&config, board, ShotTrigger::Button),
+            InitialisationType::Profile => do_auto_espresso_with_pressure_profile(&config, board),
+            InitialisationType::Paddle => do_paddle_espresso(&config, board, ShotTrigger::Button),
+        }
+    } else {
+        println!("EspressoConfig is not initialized");
+    }
+}
+
+// Programmed and paddle shots can also be pulled from the tablet, analog shots need the
+// switch held.
+pub fn do_espresso_from_ble(board: &mut Board) {
+    if let Some(config_lock) = ESPRESSO_CONFIG.get() {
+        let config = config_lock.lock().unwrap();
+        configure_puck_resistance(&config._shot_config.puck_resistance);
+        match config.initialisation_type {
+            InitialisationType::Program => do_auto_espresso(&config, board, ShotTrigger::Ble),
+            InitialisationType::Paddle => do_paddle_espresso(&config, board, ShotTrigger::Ble),
+            _ => println!(
+                "{:?} shots can't be started over ble",
+                config.initialisation_type
+            ),
         }
     } else {
         println!("EspressoConfig is not initialized");
@@ -5,8 +5,14 @@ use std::{
 };
 
 use crate::{
-    actuators::{psm::calculate_cps, pump::get_pump_flow},
+    actuators::{
+        pump::{get_commanded_cps, get_pump_flow},
+        three_way_valve::ValveState,
+    },
     board::board::Board,
+    functional::{
+        handoff::SNAPSHOTS, puck_resistance::get_puck_resistance_config, shot_series::ShotStage,
+    },
     sensors::{
         flow::{self, calculate_espresso_flow},
         pressure::read_pressure,
@@ -17,6 +23,9 @@ use crate::{
 use anyhow::Result;
 use serde::Serialize;
 
+// At the control rate a shot would otherwise stack thousands of snapshots.
+const MAX_STACKED_SNAPSHOTS: usize = 8;
+
 #[derive(Clone, Serialize)]
 pub struct EspressoStateSnapshot {
     pub pressure: f32,
@@ -24,11 +33,24 @@ pub struct EspressoStateSnapshot {
     pub estimated_espresso_flow: f32,
     pub time: SystemTime,
     pub elapsed_time_from_last_read: Duration,
+    // Falls back on the pump model without the flow meters, then it is the water pumped.
     pub estimated_weight: f32,
+    // Only the flow the meters measured, None until they see the first drip. Anything
+    // looking for the first drips in the cup uses this one.
+    pub measured_weight: Option<f32>,
     pub measured_flow: flow::Flow,
     pub espresso_flow: f32,
     pub pressure_change_speed: f32,
     pub pump_flow: f32,
+    pub valve_state: ValveState,
+    // Counts every valve toggle, the shot recorder logs a transition when it moves.
+    pub valve_transitions: u32,
+    pub profile_phase: Option<usize>,
+    pub shot_stage: Option<ShotStage>,
+    // Smoothed, None while there is too little flow for it to mean anything.
+    pub puck_resistance: Option<f32>,
+    // Set by the loops that follow a live target, e.g. the paddle.
+    pub target_pressure: Option<f32>,
 }
 impl fmt::Debug for EspressoStateSnapshot {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -42,10 +64,17 @@ impl fmt::Debug for EspressoStateSnapshot {
                 &self.elapsed_time_from_last_read,
             ) // Assuming Modem doesn't implement Debug
             .field("estimated_weight", &self.estimated_weight) // Assuming Modem doesn't implement Debug
+            .field("measured_weight", &self.measured_weight)
             .field("measured_flow", &self.measured_flow) // Assuming Modem doesn't implement Debug
             .field("espresso_flow", &self.espresso_flow) // Assuming Modem doesn't implement Debug
             .field("pressure_change_speed", &self.pressure_change_speed) // Assuming Modem doesn't implement Debug
             .field("pump_flow", &self.pump_flow) // Assuming Modem doesn't implement Debug
+            .field("valve_state", &self.valve_state)
+            .field("valve_transitions", &self.valve_transitions)
+            .field("profile_phase", &self.profile_phase)
+            .field("shot_stage", &self.shot_stage)
+            .field("puck_resistance", &self.puck_resistance)
+            .field("target_pressure", &self.target_pressure)
             .finish()
     }
 }
@@ -59,39 +88,70 @@ impl EspressoStateSnapshot {
             Ok(temp) => temp,
         };
         let current_time = SystemTime::now();
-        let cps = calculate_cps();
+        let cps = get_commanded_cps();
         let elapsed_time = match calculate_elapsed_time_from_last_snapshot(current_time) {
             Ok(time) => time,
             Err(err) => Duration::new(0, 0),
         };
+        let espresso_flow = calculate_espresso_flow()?;
+        let pump_flow = get_pump_flow(cps, &pressure);
+        // Until the flow meters are wired the pump model is the best guess of what reaches the cup.
+        let estimated_espresso_flow = if espresso_flow > 0.0 {
+            espresso_flow
+        } else {
+            pump_flow
+        };
         let espresso_snapshot = EspressoStateSnapshot {
             pressure: pressure,
             boiler_temp: temperature::read_temperature()?,
-            estimated_espresso_flow: 0.0,
-            estimated_weight: 0.0,
+            estimated_espresso_flow,
+            estimated_weight: calculate_weight(estimated_espresso_flow, elapsed_time),
+            measured_weight: calculate_measured_weight(espresso_flow, elapsed_time),
             measured_flow: flow::Flow {
                 enter: 0.0,
                 exit: 0.0,
             },
             time: current_time,
             elapsed_time_from_last_read: elapsed_time,
-            espresso_flow: calculate_espresso_flow()?,
+            espresso_flow,
             // TODO calculate pressure change speed
             pressure_change_speed: 0.0,
-            pump_flow: get_pump_flow(cps, &pressure),
+            pump_flow,
+            valve_state: board.three_way_valve.state(),
+            valve_transitions: board.three_way_valve.transitions(),
+            profile_phase: None,
+            shot_stage: None,
+            puck_resistance: calculate_puck_resistance(pressure, estimated_espresso_flow),
+            target_pressure: None,
         };
         Ok(espresso_snapshot)
     }
 }
+// Keeps the snapshot for the next reading and hands a copy off to the ble publisher.
+// Only the last few are kept, the shot recorder holds the history.
 pub fn push_snapshot(snapshot: EspressoStateSnapshot) {
+    SNAPSHOTS.publish(snapshot.clone());
     if let Some(stack) = ESPRESSO_SYSTEM_STACK.get() {
         let mut stack = stack.lock().expect("Failed to acquire lock");
+        if stack.len() >= MAX_STACKED_SNAPSHOTS {
+            stack.remove(0);
+        }
         stack.push(snapshot);
     } else {
         eprintln!("ESPRESSO_SYSTEM_STACK is not initialized");
     }
 }
 
+// Called at the start of a shot so the elapsed time and the weight start from zero.
+pub fn clear_snapshots() {
+    if let Some(stack) = ESPRESSO_SYSTEM_STACK.get() {
+        let mut stack = stack.lock().expect("Failed to acquire lock");
+        stack.clear();
+    } else {
+        eprintln!("ESPRESSO_SYSTEM_STACK is not initialized");
+    }
+}
+
 pub fn pop_snapshot() {
     if let Some(stack) = ESPRESSO_SYSTEM_STACK.get() {
         let mut stack = stack.lock().expect("Failed to acquire lock");
@@ -130,6 +190,42 @@ fn calculate_presssure_change_speed(
     }
 }
 
-fn calculated_weight() {
-    // TODO calculate the weight with the flow in and flow out.
+fn calculate_puck_resistance(pressure: f32, espresso_flow: f32) -> Option<f32> {
+    let previous = match ESPRESSO_SYSTEM_STACK.get() {
+        Some(stack) => {
+            let stack = stack.lock().expect("Failed to acquire lock");
+            stack.last().and_then(|snapshot| snapshot.puck_resistance)
+        }
+        None => None,
+    };
+    get_puck_resistance_config().smooth(previous, pressure, espresso_flow)
+}
+
+// Integrates the espresso flow since the last snapshot, assuming 1ml of espresso weighs ~1g.
+fn calculate_weight(espresso_flow: f32, elapsed_time: Duration) -> f32 {
+    let last_weight = match ESPRESSO_SYSTEM_STACK.get() {
+        Some(stack) => {
+            let stack = stack.lock().expect("Failed to acquire lock");
+            stack
+                .last()
+                .map(|snapshot| snapshot.estimated_weight)
+                .unwrap_or(0.0)
+        }
+        None => 0.0,
+    };
+    last_weight + espresso_flow.max(0.0) * elapsed_time.as_secs_f32()
+}
+
+fn calculate_measured_weight(espresso_flow: f32, elapsed_time: Duration) -> Option<f32> {
+    let last_weight = match ESPRESSO_SYSTEM_STACK.get() {
+        Some(stack) => {
+            let stack = stack.lock().expect("Failed to acquire lock");
+            stack.last().and_then(|snapshot| snapshot.measured_weight)
+        }
+        None => None,
+    };
+    if last_weight.is_none() && espresso_flow <= 0.0 {
+        return None;
+    }
+    Some(last_weight.unwrap_or(0.0) + espresso_flow.max(0.0) * elapsed_time.as_secs_f32())
 }
@@ -0,0 +1,162 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actuators::{
+        pump::{set_pump_full_on, set_pump_off},
+        pump_protection::get_pump_fault,
+        watchdog,
+    },
+    board::board::Board,
+    functional::{
+        control_loop::control_period,
+        espresso_state::{clear_snapshots, push_snapshot, EspressoStateSnapshot},
+        program_stop::{ProgramStop, StopRequest},
+    },
+    sensors::temperature::read_temperature,
+    storage::nvs,
+};
+
+// Rinses the group between shots: the pump runs flat out through the group with no
+// portafilter in, for a time or a volume. Nothing is recorded as a shot, the water still
+// counts in the water stats.
+
+const FLUSH_CONFIG_KEY: &str = "flush";
+// Second press of a double press, and how long a press has to be held to start a shot.
+static DOUBLE_PRESS_WINDOW: Duration = Duration::from_millis(400);
+static BUTTON_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlushConfig {
+    pub duration: Duration,
+    // Stops on this pumped volume if it comes before the duration.
+    pub volume_ml: Option<f32>,
+}
+
+impl Default for FlushConfig {
+    fn default() -> Self {
+        FlushConfig {
+            duration: Duration::from_secs(4),
+            volume_ml: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum FlushStopReason {
+    Time,
+    Volume,
+    Button,
+    Aborted,
+    PumpFault,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FlushReport {
+    pub duration: Duration,
+    // From the pump model.
+    pub volume_ml: f32,
+    pub temperature_start: f32,
+    pub temperature_end: f32,
+    pub temperature_delta: f32,
+    pub stop_reason: FlushStopReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ButtonPress {
+    // Still held after the double press window, a shot.
+    Hold,
+    DoublePress,
+    // Released and not pressed again, ignored.
+    Tap,
+}
+
+// Called when the brew button goes down, tells a held button from a double press. A shot
+// starts DOUBLE_PRESS_WINDOW later than the button went down.
+pub fn classify_press(board: &Board) -> ButtonPress {
+    let start = Instant::now();
+    let mut released = false;
+    while start.elapsed() < DOUBLE_PRESS_WINDOW * 2 {
+        watchdog::feed();
+        let pressed = board.get_button_state();
+        if !pressed {
+            released = true;
+        } else if released {
+            return ButtonPress::DoublePress;
+        } else if start.elapsed() >= DOUBLE_PRESS_WINDOW {
+            return ButtonPress::Hold;
+        }
+        thread::sleep(BUTTON_POLL);
+    }
+    ButtonPress::Tap
+}
+
+// The last config sent over ble, also used by the double press.
+pub fn get_flush_config() -> FlushConfig {
+    nvs::read_json(FLUSH_CONFIG_KEY).unwrap_or_default()
+}
+
+pub fn save_flush_config(config: &FlushConfig) {
+    if let Err(e) = nvs::queue_json(FLUSH_CONFIG_KEY, config) {
+        log::error!("Failed to store the flush config: {:?}", e);
+    }
+}
+
+pub fn do_flush(board: &mut Board, config: &FlushConfig) -> Result<FlushReport> {
+    log::info!("Starting flush {:?}", config);
+    let temperature_start = read_temperature()?;
+    // Closed in `ValveState` terms: the pump feeds the group.
+    let pressure = EspressoStateSnapshot::get_state(board)?.pressure;
+    board.three_way_valve.close(pressure)?;
+    clear_snapshots();
+    set_pump_full_on();
+
+    let start = Instant::now();
+    let mut volume_ml = 0.0;
+    let mut stop = ProgramStop::new("flush");
+    let stop_reason = loop {
+        watchdog::feed();
+        match stop.check(board) {
+            Some(StopRequest::Button) => break FlushStopReason::Button,
+            Some(StopRequest::Abort) => break FlushStopReason::Aborted,
+            None => {}
+        }
+        if get_pump_fault().is_some() {
+            break FlushStopReason::PumpFault;
+        }
+        match EspressoStateSnapshot::get_state(board) {
+            Ok(snapshot) => {
+                volume_ml +=
+                    snapshot.pump_flow * snapshot.elapsed_time_from_last_read.as_secs_f32();
+                push_snapshot(snapshot);
+            }
+            Err(e) => log::error!("Failed to read the state during a flush: {:?}", e),
+        }
+        if matches!(config.volume_ml, Some(target) if volume_ml >= target) {
+            break FlushStopReason::Volume;
+        }
+        if start.elapsed() >= config.duration {
+            break FlushStopReason::Time;
+        }
+        thread::sleep(control_period());
+    };
+    set_pump_off();
+    board.three_way_valve.force_open();
+
+    let temperature_end = read_temperature()?;
+    let report = FlushReport {
+        duration: start.elapsed(),
+        volume_ml,
+        temperature_start,
+        temperature_end,
+        temperature_delta: temperature_end - temperature_start,
+        stop_reason,
+    };
+    log::info!("Flush finished {:?}", report);
+    Ok(report)
+}
@@ -0,0 +1,74 @@
+use std::{
+    marker::PhantomData,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use crate::{
+    actuators::pump_duty::PumpFault,
+    functional::{
+        control_loop::LoopStats, espresso_state::EspressoStateSnapshot, presets::StoredPreset,
+        shot_analysis::ShotEvent, shot_history::ShotRecord, shot_timer::ShotTimerState,
+    },
+};
+
+// Lock-free handoff from the control task to the ble and logging side. The control task
+// publishes its latest value and never waits on a reader: a value that wasn't picked up in
+// time is replaced by the newer one, readers only ever care about the latest state.
+
+pub static SNAPSHOTS: Mailbox<EspressoStateSnapshot> = Mailbox::new();
+pub static LOOP_STATS: Mailbox<LoopStats> = Mailbox::new();
+// All the events of the running shot, so a list that wasn't sent in time loses nothing.
+pub static SHOT_EVENTS: Mailbox<Vec<ShotEvent>> = Mailbox::new();
+pub static SHOT_TIMER: Mailbox<ShotTimerState> = Mailbox::new();
+// The latest trip of the pump protection.
+pub static PUMP_FAULTS: Mailbox<PumpFault> = Mailbox::new();
+// Finished shots waiting to be saved, the publisher takes one long before the next shot ends.
+pub static SHOT_RECORDS: Mailbox<ShotRecord> = Mailbox::new();
+// The other way round: a replay preset the publisher built from the shot history, saved by
+// the control task with the other presets.
+pub static REPLAY_PRESETS: Mailbox<StoredPreset> = Mailbox::new();
+
+// A single slot holding the latest published value. Both sides swap the pointer in one
+// atomic operation, so whoever swaps a value out owns it.
+pub struct Mailbox<T> {
+    slot: AtomicPtr<T>,
+    _owns: PhantomData<Box<T>>,
+}
+
+unsafe impl<T: Send> Send for Mailbox<T> {}
+unsafe impl<T: Send> Sync for Mailbox<T> {}
+
+impl<T> Mailbox<T> {
+    pub const fn new() -> Mailbox<T> {
+        Mailbox {
+            slot: AtomicPtr::new(ptr::null_mut()),
+            _owns: PhantomData,
+        }
+    }
+
+    pub fn publish(&self, value: T) {
+        let previous = self
+            .slot
+            .swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        if !previous.is_null() {
+            drop(unsafe { Box::from_raw(previous) });
+        }
+    }
+
+    // The latest value published since the last take, if any.
+    pub fn take(&self) -> Option<T> {
+        let value = self.slot.swap(ptr::null_mut(), Ordering::AcqRel);
+        if value.is_null() {
+            None
+        } else {
+            Some(*unsafe { Box::from_raw(value) })
+        }
+    }
+}
+
+impl<T> Drop for Mailbox<T> {
+    fn drop(&mut self) {
+        drop(self.take());
+    }
+}
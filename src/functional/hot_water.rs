@@ -0,0 +1,219 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actuators::{
+        pump::{set_pump_full_on, set_pump_off},
+        pump_protection::get_pump_fault,
+        watchdog,
+    },
+    board::board::{Board, BoillerState},
+    coffee_machine::config::{get_machine_mode, set_machine_mode, MachineMode},
+    functional::{
+        control_loop::control_period,
+        espresso_state::{clear_snapshots, push_snapshot, EspressoStateSnapshot},
+        program_stop::ProgramStop,
+    },
+    sensors::temperature::read_temperature,
+};
+
+// Dispenses a set volume of hot water out of the wand, e.g. for an americano. The valve
+// stays open so the pump pushes the water through the boiler, the user opens the hot water
+// knob. The volume comes from the pump model.
+
+static PROGRESS_PERIOD: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HotWaterConfig {
+    pub volume_ml: f32,
+    // The boiler is held around this temperature, dispensing waits until it is reached.
+    pub temperature: Option<f32>,
+    // How far under the target the water can start.
+    pub temperature_tolerance: f32,
+    pub max_heat_time: Duration,
+    // Stops the pump if the volume is never reached, e.g. with the knob closed.
+    pub max_time: Duration,
+}
+
+impl Default for HotWaterConfig {
+    fn default() -> Self {
+        HotWaterConfig {
+            volume_ml: 150.0,
+            temperature: None,
+            temperature_tolerance: 2.0,
+            max_heat_time: Duration::from_secs(120),
+            max_time: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum HotWaterPhase {
+    Heating,
+    Dispensing,
+    Done,
+    Aborted,
+    // The boiler didn't reach the target within `max_heat_time`.
+    HeatTimeout,
+    // `max_time` ran out before the volume.
+    Timeout,
+    PumpFault,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HotWaterProgress {
+    pub phase: HotWaterPhase,
+    pub volume_ml: f32,
+    pub target_volume_ml: f32,
+    pub temperature: f32,
+    pub target_temperature: Option<f32>,
+    pub elapsed: Duration,
+}
+
+fn notify_progress(board: &Board, progress: &HotWaterProgress) {
+    match serde_json::to_string(progress) {
+        Ok(json) => board.notify_characteristic("hot_water_progress", json.as_bytes()),
+        Err(e) => log::error!("Failed to serialize hot water progress: {:?}", e),
+    }
+}
+
+// Bang-bang on the heater around the target, like the descale cap.
+fn hold_temperature(board: &mut Board, target: Option<f32>, temperature: f32) {
+    let Some(target) = target else {
+        return;
+    };
+    let state = if temperature >= target {
+        BoillerState::Off
+    } else {
+        BoillerState::On
+    };
+    if board.boiller_state != state {
+        if let Err(e) = board.set_boiler(state) {
+            log::error!("Failed to set boiler for hot water: {:?}", e);
+        }
+    }
+}
+
+fn heat(
+    board: &mut Board,
+    config: &HotWaterConfig,
+    progress: &mut HotWaterProgress,
+    stop: &mut ProgramStop,
+) -> Result<Option<HotWaterPhase>> {
+    let Some(target) = config.temperature else {
+        return Ok(None);
+    };
+    let start = Instant::now();
+    let mut last_progress = Instant::now();
+    loop {
+        watchdog::feed();
+        progress.temperature = read_temperature()?;
+        progress.elapsed = start.elapsed();
+        hold_temperature(board, config.temperature, progress.temperature);
+        if progress.temperature >= target - config.temperature_tolerance {
+            return Ok(None);
+        }
+        if stop.check(board).is_some() {
+            return Ok(Some(HotWaterPhase::Aborted));
+        }
+        if start.elapsed() >= config.max_heat_time {
+            return Ok(Some(HotWaterPhase::HeatTimeout));
+        }
+        if last_progress.elapsed() >= PROGRESS_PERIOD {
+            notify_progress(board, progress);
+            last_progress = Instant::now();
+        }
+        thread::sleep(control_period());
+    }
+}
+
+fn dispense(
+    board: &mut Board,
+    config: &HotWaterConfig,
+    progress: &mut HotWaterProgress,
+    stop: &mut ProgramStop,
+) -> Result<HotWaterPhase> {
+    board.three_way_valve.force_open();
+    clear_snapshots();
+    set_pump_full_on();
+    let start = Instant::now();
+    let mut last_progress = Instant::now();
+    loop {
+        watchdog::feed();
+        if stop.check(board).is_some() {
+            return Ok(HotWaterPhase::Aborted);
+        }
+        if get_pump_fault().is_some() {
+            return Ok(HotWaterPhase::PumpFault);
+        }
+        match EspressoStateSnapshot::get_state(board) {
+            Ok(snapshot) => {
+                progress.volume_ml +=
+                    snapshot.pump_flow * snapshot.elapsed_time_from_last_read.as_secs_f32();
+                progress.temperature = snapshot.boiler_temp;
+                push_snapshot(snapshot);
+            }
+            Err(e) => log::error!("Failed to read the state while dispensing: {:?}", e),
+        }
+        hold_temperature(board, config.temperature, progress.temperature);
+        progress.elapsed = start.elapsed();
+        if progress.volume_ml >= config.volume_ml {
+            return Ok(HotWaterPhase::Done);
+        }
+        if start.elapsed() >= config.max_time {
+            return Ok(HotWaterPhase::Timeout);
+        }
+        if last_progress.elapsed() >= PROGRESS_PERIOD {
+            notify_progress(board, progress);
+            last_progress = Instant::now();
+        }
+        thread::sleep(control_period());
+    }
+}
+
+pub fn do_hot_water(board: &mut Board, config: &HotWaterConfig) -> Result<HotWaterProgress> {
+    log::info!("Starting hot water {:?}", config);
+    let mut progress = HotWaterProgress {
+        phase: HotWaterPhase::Heating,
+        volume_ml: 0.0,
+        target_volume_ml: config.volume_ml,
+        temperature: read_temperature()?,
+        target_temperature: config.temperature,
+        elapsed: Duration::ZERO,
+    };
+    // Holding the temperature switches the heater, both are put back once the water is done.
+    let boiler_state = board.boiller_state;
+    let machine_mode = get_machine_mode();
+    set_machine_mode(MachineMode::HotWater);
+    notify_progress(board, &progress);
+
+    let mut stop = ProgramStop::new("hot water");
+    let result = match heat(board, config, &mut progress, &mut stop) {
+        Ok(None) => {
+            progress.phase = HotWaterPhase::Dispensing;
+            notify_progress(board, &progress);
+            dispense(board, config, &mut progress, &mut stop)
+        }
+        Ok(Some(phase)) => Ok(phase),
+        Err(e) => Err(e),
+    };
+
+    set_pump_off();
+    board.three_way_valve.force_open();
+    if board.boiller_state != boiler_state {
+        if let Err(e) = board.set_boiler(boiler_state) {
+            log::error!("Failed to restore the boiler state: {:?}", e);
+        }
+    }
+    set_machine_mode(machine_mode);
+
+    progress.phase = result?;
+    log::info!("Hot water finished {:?}", progress);
+    notify_progress(board, &progress);
+    Ok(progress)
+}
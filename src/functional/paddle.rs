@@ -0,0 +1,102 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// Lever-like manual shots: the target pressure follows a paddle, either a potentiometer on
+// the machine or the tablet's slider, and goes through a dead band and a slew limit so the
+// pump doesn't chase the noise or a flick of the slider.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PaddleSource {
+    Potentiometer,
+    // Written to the `paddle` characteristic.
+    Ble,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaddleConfig {
+    pub source: PaddleSource,
+    // Pressure at full travel of the paddle, the slider is clamped to it too.
+    pub max_pressure: f32,
+    // Potentiometer readings at the rest and full travel stops.
+    pub potentiometer_min: f32,
+    pub potentiometer_max: f32,
+    // Moves of the input smaller than this (bar) keep the previous target.
+    pub dead_band: f32,
+    // Fastest change of the target, bar/s.
+    pub slew_rate: f32,
+}
+
+impl Default for PaddleConfig {
+    fn default() -> Self {
+        PaddleConfig {
+            source: PaddleSource::Potentiometer,
+            max_pressure: 10.0,
+            potentiometer_min: 0.05,
+            potentiometer_max: 0.95,
+            dead_band: 0.2,
+            slew_rate: 5.0,
+        }
+    }
+}
+
+impl PaddleConfig {
+    pub fn potentiometer_pressure(&self, position: f32) -> f32 {
+        let travel = (self.potentiometer_max - self.potentiometer_min).max(f32::EPSILON);
+        ((position - self.potentiometer_min) / travel).clamp(0.0, 1.0) * self.max_pressure
+    }
+}
+
+// f32 bits of the slider's pressure, written from the ble callback.
+static BLE_TARGET: AtomicU32 = AtomicU32::new(0);
+
+// The slider writes the pressure as a json number, e.g. `6.5`.
+pub fn set_ble_target(data: &[u8]) -> Result<f32> {
+    let pressure: f32 = serde_json::from_slice(data)?;
+    let pressure = pressure.max(0.0);
+    BLE_TARGET.store(pressure.to_bits(), Ordering::Relaxed);
+    Ok(pressure)
+}
+
+pub fn get_ble_target() -> f32 {
+    f32::from_bits(BLE_TARGET.load(Ordering::Relaxed))
+}
+
+// So the next shot doesn't start at the pressure the last one ended on.
+pub fn reset_ble_target() {
+    BLE_TARGET.store(0.0f32.to_bits(), Ordering::Relaxed);
+}
+
+pub struct PaddleFilter {
+    config: PaddleConfig,
+    // Input value the dead band is measured from.
+    held: f32,
+    target: f32,
+}
+
+impl PaddleFilter {
+    pub fn new(config: PaddleConfig) -> PaddleFilter {
+        PaddleFilter {
+            config,
+            held: 0.0,
+            target: 0.0,
+        }
+    }
+
+    // Takes the paddle's pressure, returns the target for this control cycle.
+    pub fn update(&mut self, requested: f32, dt: Duration) -> f32 {
+        let requested = requested.clamp(0.0, self.config.max_pressure);
+        // The stops always get through, the target can't be stuck just above zero.
+        let at_stop = requested == 0.0 || requested == self.config.max_pressure;
+        if at_stop || (requested - self.held).abs() >= self.config.dead_band {
+            self.held = requested;
+        }
+        let max_step = self.config.slew_rate * dt.as_secs_f32();
+        self.target += (self.held - self.target).clamp(-max_step, max_step);
+        self.target
+    }
+}
@@ -0,0 +1,150 @@
+use std::{sync::Mutex, thread, time::Duration};
+
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::{
+    actuators::{pump::set_pump_off, watchdog},
+    board::board::Board,
+    functional::{
+        control_loop::control_period,
+        drip_compensation::{DripCompensation, LearnResult, StopPoint, MAX_DRIP_LAG},
+        espresso_state::{push_snapshot, EspressoStateSnapshot},
+    },
+    storage::nvs,
+};
+
+// Stops programmed shots early by the espresso still on its way to the cup. The final weight
+// is predicted as weight + flow * drip lag, and the lag is learned per machine from the
+// settled weight after each shot, measured by the flow meters or reported by the tablet.
+// Only shots with a measured cup weight at the stop are learned from, see `LearnResult`.
+
+const DRIP_KEY: &str = "drip";
+// The control task reads nothing else while the drip settles.
+const MAX_SETTLE_TIME: Duration = Duration::from_secs(15);
+
+static DRIP_COMPENSATION: OnceCell<Mutex<DripCompensation>> = OnceCell::new();
+// The last predicted stop, waiting for its settled weight.
+static PENDING_STOP: Mutex<Option<StopPoint>> = Mutex::new(None);
+
+// Settings the tablet can write to the `drip_compensation` characteristic.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DripSettings {
+    pub enabled: Option<bool>,
+    pub drip_lag: Option<f32>,
+    pub learning_rate: Option<f32>,
+    pub settle_time: Option<Duration>,
+}
+
+fn with_compensation<T>(f: impl FnOnce(&mut DripCompensation) -> T) -> Option<T> {
+    DRIP_COMPENSATION
+        .get()
+        .map(|compensation| f(&mut compensation.lock().expect("Failed to acquire lock")))
+}
+
+pub fn init_drip_compensation() {
+    let compensation = nvs::read_json(DRIP_KEY).unwrap_or_default();
+    DRIP_COMPENSATION.set(Mutex::new(compensation)).ok();
+}
+
+pub fn get_drip_compensation() -> DripCompensation {
+    with_compensation(|compensation| compensation.clone()).unwrap_or_default()
+}
+
+pub fn apply_drip_settings(settings: DripSettings) -> Result<DripCompensation> {
+    with_compensation(|compensation| {
+        if let Some(enabled) = settings.enabled {
+            compensation.enabled = enabled;
+        }
+        if let Some(drip_lag) = settings.drip_lag {
+            compensation.drip_lag = drip_lag.clamp(0.0, MAX_DRIP_LAG);
+        }
+        if let Some(learning_rate) = settings.learning_rate {
+            compensation.learning_rate = learning_rate.clamp(0.0, 1.0);
+        }
+        if let Some(settle_time) = settings.settle_time {
+            compensation.settle_time = settle_time.min(MAX_SETTLE_TIME);
+        }
+        nvs::write_json(DRIP_KEY, compensation)?;
+        Ok(compensation.clone())
+    })
+    .unwrap_or_else(|| anyhow::bail!("Drip compensation is not initialized"))
+}
+
+fn notify_drip_compensation(board: &Board) {
+    let compensation = serde_json::to_string(&get_drip_compensation()).unwrap_or_default();
+    board.notify_characteristic("drip_compensation", compensation.as_bytes());
+}
+
+// Called when a shot starts, a settled weight that never came is dropped.
+pub fn clear_pending_stop() {
+    *PENDING_STOP.lock().expect("Failed to acquire lock") = None;
+}
+
+// Refines the lag with the settled weight of the last predicted stop.
+pub fn learn_settled_weight(board: &Board, settled_weight: f32) {
+    let stop = match PENDING_STOP.lock().expect("Failed to acquire lock").take() {
+        Some(stop) => stop,
+        None => {
+            log::info!("No predicted stop waiting for a settled weight");
+            return;
+        }
+    };
+    let learned = with_compensation(|compensation| {
+        match compensation.learn(&stop, settled_weight) {
+            LearnResult::Learned => {}
+            LearnResult::NoMeasuredWeight => {
+                log::info!("No measured cup weight at the stop, the drip lag is kept");
+                return false;
+            }
+            LearnResult::LowFlow => return false,
+        }
+        log::info!(
+            "Drip lag now {:.2}s, settled {:.1}g for a {:.1}g target",
+            compensation.drip_lag,
+            settled_weight,
+            stop.target_weight
+        );
+        if let Err(e) = nvs::queue_json(DRIP_KEY, compensation) {
+            log::error!("Failed to store the drip compensation: {:?}", e);
+        }
+        true
+    });
+    if learned == Some(true) {
+        notify_drip_compensation(board);
+    }
+}
+
+// Vents the group and keeps reading the flow meters for the settle time after a predicted
+// stop, call it straight after the stop. Without meters (or without any measured drip) the
+// stop waits for the tablet to report the settled weight.
+pub fn settle_after_stop(board: &mut Board, stop: StopPoint) {
+    set_pump_off();
+    board.three_way_valve.force_open();
+    *PENDING_STOP.lock().expect("Failed to acquire lock") = Some(stop);
+    let settle_time = get_drip_compensation().settle_time.min(MAX_SETTLE_TIME);
+    let mut measured_drip = false;
+    let mut settled_weight = stop.measured_weight;
+    while stop.time.elapsed() < settle_time {
+        watchdog::feed();
+        match EspressoStateSnapshot::get_state(board) {
+            Ok(snapshot) => {
+                measured_drip |= snapshot.espresso_flow > 0.0;
+                settled_weight = snapshot.measured_weight;
+                push_snapshot(snapshot);
+            }
+            Err(e) => {
+                log::error!("Failed to read the drip after the stop: {:?}", e);
+                return;
+            }
+        }
+        thread::sleep(control_period());
+    }
+    match settled_weight {
+        Some(settled_weight) if measured_drip => learn_settled_weight(board, settled_weight),
+        _ => {
+            log::info!("No drip measured, waiting for the settled weight from the tablet")
+        }
+    }
+}
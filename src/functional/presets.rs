@@ -0,0 +1,215 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    functional::{espresso::EspressoType, profile::Profile},
+    storage::nvs,
+};
+
+// Ids of the stored presets in list order, each preset has its own key so one with a long
+// profile can't push the others over the nvs value size.
+const PRESET_INDEX_KEY: &str = "preset_index";
+pub const MAX_CUSTOM_PRESETS: usize = 8;
+// Largest ble attribute value, the `presets` characteristic carries the summary list.
+const MAX_SUMMARY_LEN: usize = 512;
+
+// Used for profiles that don't say, e.g. imported ones.
+const DEFAULT_DOSE: f32 = 18.0;
+const DEFAULT_RATIO: f32 = 2.0;
+const DEFAULT_PRESSURE: f32 = 9.0;
+const DEFAULT_TEMPERATURE: u8 = 93;
+const DEFAULT_MAX_TIME: u32 = 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PresetId {
+    BuiltIn(EspressoType),
+    Custom(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShotPreset {
+    pub name: String,
+    // Grams of coffee in the basket.
+    pub dose: f32,
+    // Yield over dose, 2.0 pulls 36g out of 18g.
+    pub ratio: f32,
+    // Brew pressure when there is no profile.
+    pub pressure: f32,
+    pub profile: Option<Profile>,
+    pub temperature: u8,
+    pub max_time: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredPreset {
+    pub id: PresetId,
+    pub preset: ShotPreset,
+}
+
+// Where a stored preset is kept, `slot` picks its nvs key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PresetSlot {
+    id: PresetId,
+    slot: u8,
+}
+
+fn preset_key(slot: u8) -> String {
+    format!("preset_{}", slot)
+}
+
+fn read_index() -> Vec<PresetSlot> {
+    nvs::read_json(PRESET_INDEX_KEY).unwrap_or_default()
+}
+
+// What the `presets` characteristic lists, the full presets don't fit in one value.
+#[derive(Debug, Clone, Serialize)]
+pub struct PresetSummary {
+    pub id: PresetId,
+    pub name: String,
+}
+
+fn built_in_preset(espresso_type: EspressoType) -> ShotPreset {
+    let (dose, ratio, temperature, max_time) = match espresso_type {
+        EspressoType::Single => (9.0, 2.0, 93, 30),
+        EspressoType::Double => (18.0, 2.0, 93, 35),
+        EspressoType::Ristretto => (18.0, 1.0, 93, 25),
+        EspressoType::Lungo => (18.0, 3.0, 92, 45),
+    };
+    ShotPreset {
+        name: format!("{:?}", espresso_type),
+        dose,
+        ratio,
+        pressure: 9.0,
+        profile: None,
+        temperature,
+        max_time,
+    }
+}
+
+// A preset running `profile`, the ratio comes from the target weight when there is one.
+pub fn profile_preset(
+    profile: Profile,
+    dose: Option<f32>,
+    target_weight: Option<f32>,
+) -> ShotPreset {
+    let dose = dose.filter(|dose| *dose > 0.0).unwrap_or(DEFAULT_DOSE);
+    let ratio = target_weight
+        .map(|target_weight| target_weight / dose)
+        .filter(|ratio| *ratio > 0.0)
+        .unwrap_or(DEFAULT_RATIO);
+    let temperature = profile
+        .temperature
+        .map(|temperature| temperature.round().clamp(0.0, u8::MAX as f32) as u8)
+        .unwrap_or(DEFAULT_TEMPERATURE);
+    ShotPreset {
+        name: profile.name.clone(),
+        dose,
+        ratio,
+        pressure: DEFAULT_PRESSURE,
+        profile: Some(profile),
+        temperature,
+        max_time: DEFAULT_MAX_TIME,
+    }
+}
+
+// The stored presets, with the factory values for any built-in that was never edited.
+pub fn get_presets() -> Vec<StoredPreset> {
+    let mut presets: Vec<StoredPreset> = read_index()
+        .into_iter()
+        .filter_map(|entry| match nvs::read_json(&preset_key(entry.slot)) {
+            Some(preset) => Some(StoredPreset {
+                id: entry.id,
+                preset,
+            }),
+            None => {
+                log::warn!("Preset {:?} is missing from nvs", entry.id);
+                None
+            }
+        })
+        .collect();
+    for espresso_type in EspressoType::ALL {
+        let id = PresetId::BuiltIn(espresso_type);
+        if !presets.iter().any(|stored| stored.id == id) {
+            presets.push(StoredPreset {
+                id,
+                preset: built_in_preset(espresso_type),
+            });
+        }
+    }
+    presets
+}
+
+fn summarise(presets: &[StoredPreset]) -> Vec<PresetSummary> {
+    presets
+        .iter()
+        .map(|stored| PresetSummary {
+            id: stored.id.clone(),
+            name: stored.preset.name.clone(),
+        })
+        .collect()
+}
+
+pub fn get_preset_summaries() -> Vec<PresetSummary> {
+    summarise(&get_presets())
+}
+
+pub fn get_preset(id: &PresetId) -> Option<ShotPreset> {
+    get_presets()
+        .into_iter()
+        .find(|stored| &stored.id == id)
+        .map(|stored| stored.preset)
+}
+
+pub fn save_preset(id: PresetId, preset: ShotPreset) -> Result<()> {
+    let mut index = read_index();
+    let mut presets = get_presets();
+    match presets.iter_mut().find(|stored| stored.id == id) {
+        Some(stored) => stored.preset = preset.clone(),
+        None => {
+            let custom_presets = presets
+                .iter()
+                .filter(|stored| matches!(stored.id, PresetId::Custom(_)))
+                .count();
+            if custom_presets >= MAX_CUSTOM_PRESETS {
+                bail!("Only {} custom presets can be stored", MAX_CUSTOM_PRESETS);
+            }
+            presets.push(StoredPreset {
+                id: id.clone(),
+                preset: preset.clone(),
+            });
+        }
+    }
+    let summary_len = serde_json::to_vec(&summarise(&presets))?.len();
+    if summary_len > MAX_SUMMARY_LEN {
+        bail!(
+            "The preset list would take {} bytes, over {}, use shorter names",
+            summary_len,
+            MAX_SUMMARY_LEN
+        );
+    }
+    let slot = match index.iter().find(|entry| entry.id == id) {
+        Some(entry) => entry.slot,
+        None => {
+            let slot = (0..=u8::MAX)
+                .find(|slot| !index.iter().any(|entry| entry.slot == *slot))
+                .ok_or_else(|| anyhow!("No free preset slot"))?;
+            index.push(PresetSlot { id, slot });
+            slot
+        }
+    };
+    // Queued before the index, so the index never lists a preset that isn't written yet.
+    nvs::queue_json(&preset_key(slot), &preset)?;
+    nvs::queue_json(PRESET_INDEX_KEY, &index)
+}
+
+// Custom presets are removed, built-ins go back to their factory values.
+pub fn delete_preset(id: &PresetId) -> Result<()> {
+    let mut index = read_index();
+    let Some(position) = index.iter().position(|entry| &entry.id == id) else {
+        return Ok(());
+    };
+    let entry = index.remove(position);
+    nvs::queue_json(PRESET_INDEX_KEY, &index)?;
+    nvs::queue_remove(&preset_key(entry.slot));
+    Ok(())
+}
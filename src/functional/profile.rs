@@ -0,0 +1,228 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::actuators::controller_config::PressureControllerType;
+
+// Phase based pressure/flow profiles. This module only does the maths, it doesn't touch the
+// board, so it can be run and checked on the host with recorded or synthetic inputs.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ControlType {
+    Pressure,
+    Flow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TransitionCurve {
+    Instant,
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl TransitionCurve {
+    // Maps the transition progress (0.0..=1.0) to the fraction of the way to the end target.
+    pub fn apply(&self, progress: f32) -> f32 {
+        let t = progress.clamp(0.0, 1.0);
+        match self {
+            TransitionCurve::Instant => 1.0,
+            TransitionCurve::Linear => t,
+            TransitionCurve::EaseIn => t * t,
+            TransitionCurve::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            TransitionCurve::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ExitCondition {
+    // Time spent in the phase.
+    Time(Duration),
+    // Weight in the cup since the start of the shot.
+    Weight(f32),
+    PressureAbove(f32),
+    PressureBelow(f32),
+    FlowAbove(f32),
+    FlowBelow(f32),
+    // Puck resistance, see `puck_resistance`. Never met while there is no resistance yet.
+    ResistanceAbove(f32),
+    ResistanceBelow(f32),
+    // Any([]) never exits, All([]) exits straight away.
+    Any(Vec<ExitCondition>),
+    All(Vec<ExitCondition>),
+}
+
+impl ExitCondition {
+    pub fn is_met(&self, input: &ProfileInput, phase_time: Duration) -> bool {
+        match self {
+            ExitCondition::Time(time) => phase_time >= *time,
+            ExitCondition::Weight(weight) => input.weight >= *weight,
+            ExitCondition::PressureAbove(pressure) => input.pressure > *pressure,
+            ExitCondition::PressureBelow(pressure) => input.pressure < *pressure,
+            ExitCondition::FlowAbove(flow) => input.flow > *flow,
+            ExitCondition::FlowBelow(flow) => input.flow < *flow,
+            ExitCondition::ResistanceAbove(resistance) => input
+                .resistance
+                .is_some_and(|input_resistance| input_resistance > *resistance),
+            ExitCondition::ResistanceBelow(resistance) => input
+                .resistance
+                .is_some_and(|input_resistance| input_resistance < *resistance),
+            ExitCondition::Any(conditions) => conditions
+                .iter()
+                .any(|condition| condition.is_met(input, phase_time)),
+            ExitCondition::All(conditions) => conditions
+                .iter()
+                .all(|condition| condition.is_met(input, phase_time)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Phase {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub control: ControlType,
+    // None starts from where the previous phase ended, or from the measured value for the
+    // first phase.
+    #[serde(default)]
+    pub start: Option<f32>,
+    pub end: f32,
+    pub transition: TransitionCurve,
+    pub transition_time: Duration,
+    // Flow limit for pressure phases and pressure limit for flow phases, 0.0 is no limit.
+    #[serde(default)]
+    pub restriction: f32,
+    pub exit: ExitCondition,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub phases: Vec<Phase>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    // Checked every cycle against the whole shot, `Time` is the shot time here.
+    #[serde(default)]
+    pub global_exit: Option<ExitCondition>,
+    // None runs the profile with the shot config's controller.
+    #[serde(default)]
+    pub pressure_controller: Option<PressureControllerType>,
+}
+
+// Measurements the engine needs each control cycle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfileInput {
+    pub shot_time: Duration,
+    pub pressure: f32,
+    pub flow: f32,
+    pub weight: f32,
+    pub resistance: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ProfileTarget {
+    pub phase: usize,
+    pub control: ControlType,
+    pub target: f32,
+    pub restriction: f32,
+}
+
+pub struct ProfileEngine {
+    profile: Profile,
+    phase_index: usize,
+    phase_started: Option<Duration>,
+    phase_start_value: f32,
+}
+
+impl ProfileEngine {
+    pub fn new(profile: Profile) -> ProfileEngine {
+        ProfileEngine {
+            profile,
+            phase_index: 0,
+            phase_started: None,
+            phase_start_value: 0.0,
+        }
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    pub fn current_phase(&self) -> Option<usize> {
+        if self.is_finished() {
+            None
+        } else {
+            Some(self.phase_index)
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.phase_index >= self.profile.phases.len()
+    }
+
+    // Advances through the phases whose exit conditions are met and returns the target for
+    // this cycle, None once the last phase has exited.
+    pub fn step(&mut self, input: &ProfileInput) -> Option<ProfileTarget> {
+        if let Some(global_exit) = &self.profile.global_exit {
+            if global_exit.is_met(input, input.shot_time) {
+                self.phase_index = self.profile.phases.len();
+                return None;
+            }
+        }
+        // Bounded so a profile made only of phases that exit instantly can't spin forever.
+        for _ in 0..=self.profile.phases.len() {
+            if self.is_finished() {
+                return None;
+            }
+            let phase_started = match self.phase_started {
+                Some(phase_started) => phase_started,
+                None => self.enter_phase(input),
+            };
+            let phase_time = input.shot_time.saturating_sub(phase_started);
+            let phase = &self.profile.phases[self.phase_index];
+            if phase.exit.is_met(input, phase_time) {
+                self.phase_index += 1;
+                self.phase_started = None;
+                continue;
+            }
+            return Some(ProfileTarget {
+                phase: self.phase_index,
+                control: phase.control,
+                target: self.target_at(phase_time),
+                restriction: phase.restriction,
+            });
+        }
+        None
+    }
+
+    fn enter_phase(&mut self, input: &ProfileInput) -> Duration {
+        let phase = &self.profile.phases[self.phase_index];
+        self.phase_start_value = match phase.start {
+            Some(start) => start,
+            None => match self.phase_index.checked_sub(1) {
+                Some(previous) if self.profile.phases[previous].control == phase.control => {
+                    self.profile.phases[previous].end
+                }
+                _ => match phase.control {
+                    ControlType::Pressure => input.pressure,
+                    ControlType::Flow => input.flow,
+                },
+            },
+        };
+        self.phase_started = Some(input.shot_time);
+        input.shot_time
+    }
+
+    fn target_at(&self, phase_time: Duration) -> f32 {
+        let phase = &self.profile.phases[self.phase_index];
+        let progress = if phase.transition_time.is_zero() {
+            1.0
+        } else {
+            phase_time.as_secs_f32() / phase.transition_time.as_secs_f32()
+        };
+        let fraction = phase.transition.apply(progress);
+        self.phase_start_value + (phase.end - self.phase_start_value) * fraction
+    }
+}
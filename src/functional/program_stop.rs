@@ -0,0 +1,50 @@
+use crate::{
+    board::board::Board,
+    connectivity::commands::{take_command_if, MachineCommand},
+    sensors::pressure::read_pressure,
+};
+
+// Stop check shared by the programs that run the pump outside a shot: flush, hot water,
+// backflush and descale. They stop on a new press of the brew button or `abort` from the app,
+// other commands stay queued until the program is done.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopRequest {
+    Button,
+    Abort,
+}
+
+pub struct ProgramStop {
+    program: &'static str,
+    // The press that started the program may still be held, it has to be released first.
+    button_released: bool,
+}
+
+impl ProgramStop {
+    pub fn new(program: &'static str) -> ProgramStop {
+        ProgramStop {
+            program,
+            button_released: false,
+        }
+    }
+
+    // Call it every tick of the program. It also reads the pressure, so the pump model of the
+    // water tracker follows it in programs that don't take snapshots.
+    pub fn check(&mut self, board: &mut Board) -> Option<StopRequest> {
+        if let Err(e) = read_pressure(board) {
+            log::debug!(
+                "Failed to read the pressure during the {}: {:?}",
+                self.program,
+                e
+            );
+        }
+        if !board.get_button_state() {
+            self.button_released = true;
+        } else if self.button_released {
+            return Some(StopRequest::Button);
+        }
+        let abort = take_command_if(|command| matches!(command, MachineCommand::Abort))?;
+        log::info!("{:?} during the {}", abort, self.program);
+        Some(StopRequest::Abort)
+    }
+}
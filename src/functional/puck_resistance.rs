@@ -0,0 +1,74 @@
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+// How hard the puck pushes back, computed on every snapshot. It stays about constant while
+// the puck holds and drops when it erodes or channels, whatever the profile does with the
+// pressure, so it is a better signal for moving phases than pressure or flow alone.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ResistanceModel {
+    // Pressure / flow², flow through the puck goes roughly with the square root of the
+    // pressure. The model Decent and Gaggiuino chart.
+    PressureOverFlowSquared,
+    // Pressure / flow, Darcy's law for a bed that doesn't compress.
+    PressureOverFlow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PuckResistanceConfig {
+    pub model: ResistanceModel,
+    // Below this flow (ml/s) there is no resistance, the division would blow up.
+    pub min_flow: f32,
+    // Weight of the new value in the moving average, 1.0 disables the smoothing.
+    pub smoothing: f32,
+}
+
+const DEFAULT_CONFIG: PuckResistanceConfig = PuckResistanceConfig {
+    model: ResistanceModel::PressureOverFlowSquared,
+    min_flow: 0.5,
+    smoothing: 0.2,
+};
+
+impl Default for PuckResistanceConfig {
+    fn default() -> Self {
+        DEFAULT_CONFIG
+    }
+}
+
+// Set from the shot config when a shot starts, read on every snapshot.
+static CONFIG: Mutex<PuckResistanceConfig> = Mutex::new(DEFAULT_CONFIG);
+
+pub fn configure_puck_resistance(config: &PuckResistanceConfig) {
+    *CONFIG.lock().expect("Failed to acquire lock") = *config;
+}
+
+pub fn get_puck_resistance_config() -> PuckResistanceConfig {
+    *CONFIG.lock().expect("Failed to acquire lock")
+}
+
+impl PuckResistanceConfig {
+    pub fn raw(&self, pressure: f32, flow: f32) -> Option<f32> {
+        if flow < self.min_flow.max(f32::EPSILON) {
+            return None;
+        }
+        let resistance = match self.model {
+            ResistanceModel::PressureOverFlowSquared => pressure / (flow * flow),
+            ResistanceModel::PressureOverFlow => pressure / flow,
+        };
+        Some(resistance.max(0.0))
+    }
+
+    // Moving average with the previous snapshot's value. The average restarts whenever the
+    // flow drops under `min_flow`.
+    pub fn smooth(&self, previous: Option<f32>, pressure: f32, flow: f32) -> Option<f32> {
+        let raw = self.raw(pressure, flow)?;
+        Some(match previous {
+            Some(previous) => {
+                let alpha = self.smoothing.clamp(0.0, 1.0);
+                previous + (raw - previous) * alpha
+            }
+            None => raw,
+        })
+    }
+}
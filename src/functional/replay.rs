@@ -0,0 +1,31 @@
+use anyhow::{Context, Result};
+
+use crate::functional::{
+    espresso::ShotStopReason,
+    presets::{profile_preset, ShotPreset},
+    replay_fit::{replay_profile, ReplayConfig, ReplaySource},
+    shot_history::ShotRecord,
+};
+
+// Saves a recorded shot as a preset running its replay profile, see `replay_fit`.
+
+fn replay_source(record: &ShotRecord) -> ReplaySource {
+    ReplaySource {
+        samples: &record.samples,
+        temperature: record.temperature,
+        stopped_on_weight: record.stop_reason == ShotStopReason::TargetWeight,
+        final_weight: record.final_weight,
+        duration: record.duration,
+    }
+}
+
+pub fn replay_preset(record: &ShotRecord, name: &str, config: &ReplayConfig) -> Result<ShotPreset> {
+    let profile = replay_profile(&replay_source(record), name, config)
+        .with_context(|| format!("Failed to replay shot {}", record.id))?;
+    let mut preset = profile_preset(profile, record.dose, Some(record.final_weight));
+    // Leave room for a slower puck, the profile's own exit is what normally ends the shot.
+    preset.max_time = preset
+        .max_time
+        .max((record.duration.as_secs_f32() * 1.5).ceil() as u32);
+    Ok(preset)
+}
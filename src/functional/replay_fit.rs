@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::functional::{
+    profile::{ControlType, ExitCondition, Phase, Profile, TransitionCurve},
+    shot_series::ShotSeries,
+};
+
+// Turns a recorded shot into a profile that pulls it again. The pressure (or pump flow) curve
+// is smoothed and fitted with straight segments, each segment becomes a linear phase. Kept
+// apart from the shot history so the host tools can run it.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplayConfig {
+    pub control: ControlType,
+    pub max_phases: usize,
+    // Samples averaged on each side of a point before fitting.
+    pub smoothing: usize,
+    // Segments are split until no smoothed point is further than this from the fit,
+    // in bar or ml/s depending on `control`.
+    pub tolerance: f32,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            control: ControlType::Pressure,
+            max_phases: 6,
+            smoothing: 2,
+            tolerance: 0.3,
+        }
+    }
+}
+
+// What a replay needs from a recorded shot.
+pub struct ReplaySource<'a> {
+    pub samples: &'a ShotSeries,
+    // Mean boiler temperature of the shot.
+    pub temperature: f32,
+    pub stopped_on_weight: bool,
+    pub final_weight: f32,
+    pub duration: Duration,
+}
+
+fn smooth(values: &[f32], window: usize) -> Vec<f32> {
+    (0..values.len())
+        .map(|index| {
+            let from = index.saturating_sub(window);
+            let to = (index + window + 1).min(values.len());
+            values[from..to].iter().sum::<f32>() / (to - from) as f32
+        })
+        .collect()
+}
+
+// Distance of the point from the straight line between the segment ends, along the value axis.
+fn deviation(times: &[f32], values: &[f32], from: usize, to: usize, index: usize) -> f32 {
+    let span = times[to] - times[from];
+    let progress = if span > 0.0 {
+        (times[index] - times[from]) / span
+    } else {
+        0.0
+    };
+    let fitted = values[from] + (values[to] - values[from]) * progress;
+    (values[index] - fitted).abs()
+}
+
+// Top down fit: keeps splitting the segment with the worst point at that point, so the
+// number of segments is bounded and the biggest errors go first. Returns the breakpoints.
+fn fit_segments(times: &[f32], values: &[f32], max_segments: usize, tolerance: f32) -> Vec<usize> {
+    let last = values.len() - 1;
+    let mut breakpoints = vec![0, last];
+    while breakpoints.len() - 1 < max_segments {
+        let mut worst: Option<(usize, f32)> = None;
+        for window in breakpoints.windows(2) {
+            for index in window[0] + 1..window[1] {
+                let error = deviation(times, values, window[0], window[1], index);
+                if worst.map_or(true, |(_, worst_error)| error > worst_error) {
+                    worst = Some((index, error));
+                }
+            }
+        }
+        match worst {
+            Some((index, error)) if error > tolerance => {
+                let position = breakpoints.partition_point(|breakpoint| *breakpoint < index);
+                breakpoints.insert(position, index);
+            }
+            _ => break,
+        }
+    }
+    breakpoints
+}
+
+// Stops like the original shot did: on its weight when it stopped on weight, on its time
+// otherwise.
+fn replay_exit(shot: &ReplaySource) -> ExitCondition {
+    if shot.stopped_on_weight {
+        ExitCondition::Weight(shot.final_weight)
+    } else {
+        ExitCondition::Time(shot.duration)
+    }
+}
+
+pub fn replay_profile(shot: &ReplaySource, name: &str, config: &ReplayConfig) -> Result<Profile> {
+    let samples = shot.samples;
+    // Shots that followed a live target, like paddle shots, replay the target rather than
+    // how the puck answered it.
+    let target_pressure: Option<Vec<f32>> = samples.target_pressure.iter().copied().collect();
+    let series = match (config.control, &target_pressure) {
+        (ControlType::Pressure, Some(target)) if !target.is_empty() => target,
+        (ControlType::Pressure, _) => &samples.pressure,
+        (ControlType::Flow, _) => &samples.pump_flow,
+    };
+    let length = samples.time_ms.len().min(series.len());
+    if length < 2 {
+        bail!("The shot has too few samples to replay");
+    }
+    if config.max_phases == 0 {
+        bail!("A replay needs at least one phase");
+    }
+
+    let times: Vec<f32> = samples.time_ms[..length]
+        .iter()
+        .map(|time| *time as f32 / 1000.0)
+        .collect();
+    let values = smooth(&series[..length], config.smoothing);
+    let breakpoints = fit_segments(&times, &values, config.max_phases, config.tolerance);
+
+    let segments = breakpoints.len() - 1;
+    let phases = breakpoints
+        .windows(2)
+        .enumerate()
+        .map(|(index, window)| {
+            let duration = Duration::from_secs_f32((times[window[1]] - times[window[0]]).max(0.0));
+            let is_last = index + 1 == segments;
+            Phase {
+                name: Some(format!("replay {}", index + 1)),
+                control: config.control,
+                // Later phases carry on from where the previous one ended.
+                start: if index == 0 {
+                    Some(values[window[0]])
+                } else {
+                    None
+                },
+                end: values[window[1]],
+                transition: TransitionCurve::Linear,
+                transition_time: duration,
+                restriction: 0.0,
+                // The last phase holds its end value until the shot exits.
+                exit: if is_last {
+                    ExitCondition::Any(Vec::new())
+                } else {
+                    ExitCondition::Time(duration)
+                },
+            }
+        })
+        .collect();
+
+    Ok(Profile {
+        name: name.to_string(),
+        phases,
+        temperature: Some(shot.temperature),
+        global_exit: Some(replay_exit(shot)),
+        pressure_controller: None,
+    })
+}
@@ -0,0 +1,284 @@
+use std::{collections::VecDeque, time::Duration};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// Looks for signs of channeling and badly dialled shots, live while the shot runs and once
+// it has ended. Like `profile` this only does the maths on plain samples, so the host
+// converter can run the same detectors on recorded shots.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnalysisConfig {
+    // Pressure the puck has to hold before a drop or a steady pressure means anything.
+    pub min_brew_pressure: f32,
+    // A pressure drop of `drop_pressure` within `drop_window` while the flow rises by
+    // `spike_flow`: the puck cracked.
+    pub drop_window: Duration,
+    pub drop_pressure: f32,
+    pub spike_flow: f32,
+    // The flow rising by `flow_rise` over `rise_window` with the pressure within
+    // `steady_pressure`: a channel opening up.
+    pub rise_window: Duration,
+    pub steady_pressure: f32,
+    pub flow_rise: f32,
+    // First drips in the cup before `min_first_drip`, the water found a way through.
+    pub first_drip_weight: f32,
+    pub min_first_drip: Duration,
+    // Shots reaching their target before `gusher_time`, or still short of `choker_yield`
+    // of it after `choker_time`.
+    pub gusher_time: Duration,
+    pub choker_time: Duration,
+    pub choker_yield: f32,
+    // The same live detector doesn't fire again within this time.
+    pub cooldown: Duration,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        AnalysisConfig {
+            min_brew_pressure: 4.0,
+            drop_window: Duration::from_secs(1),
+            drop_pressure: 1.5,
+            spike_flow: 1.0,
+            rise_window: Duration::from_secs(3),
+            steady_pressure: 0.5,
+            flow_rise: 0.8,
+            first_drip_weight: 0.5,
+            min_first_drip: Duration::from_secs(3),
+            gusher_time: Duration::from_secs(15),
+            choker_time: Duration::from_secs(45),
+            choker_yield: 0.7,
+            cooldown: Duration::from_secs(3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShotEventKind {
+    PressureDrop,
+    FlowRise,
+    FastFirstDrip,
+    Gusher,
+    Choker,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShotEvent {
+    pub time_ms: u32,
+    pub kind: ShotEventKind,
+    pub pressure: f32,
+    pub flow: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnalysisSample {
+    pub time_ms: u32,
+    pub pressure: f32,
+    pub flow: f32,
+    pub weight: f32,
+    // None without the flow meters, the first drip can't be told from the pump model.
+    pub measured_weight: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShotOutcome {
+    pub duration: Duration,
+    pub final_weight: f32,
+    pub target_weight: Option<f32>,
+}
+
+fn millis(duration: Duration) -> u32 {
+    duration.as_millis() as u32
+}
+
+pub struct ShotAnalyser {
+    config: AnalysisConfig,
+    // Samples over the longest detector window.
+    window: VecDeque<AnalysisSample>,
+    first_drip_ms: Option<u32>,
+    events: Vec<ShotEvent>,
+}
+
+impl ShotAnalyser {
+    pub fn new(config: AnalysisConfig) -> ShotAnalyser {
+        ShotAnalyser {
+            config,
+            window: VecDeque::new(),
+            first_drip_ms: None,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn events(&self) -> &[ShotEvent] {
+        &self.events
+    }
+
+    // Feeds the next sample, returns the event it raised if any.
+    pub fn push(&mut self, sample: AnalysisSample) -> Option<ShotEvent> {
+        let span = millis(self.config.drop_window.max(self.config.rise_window));
+        self.window.push_back(sample);
+        while let Some(oldest) = self.window.front() {
+            if oldest.time_ms + span >= sample.time_ms {
+                break;
+            }
+            self.window.pop_front();
+        }
+
+        let kind = self
+            .first_drip(&sample)
+            .or_else(|| self.pressure_drop(&sample))
+            .or_else(|| self.flow_rise(&sample))?;
+        let event = ShotEvent {
+            time_ms: sample.time_ms,
+            kind,
+            pressure: sample.pressure,
+            flow: sample.flow,
+        };
+        self.events.push(event.clone());
+        Some(event)
+    }
+
+    fn cooling_down(&self, kind: ShotEventKind, time_ms: u32) -> bool {
+        self.events
+            .iter()
+            .rev()
+            .find(|event| event.kind == kind)
+            .is_some_and(|event| event.time_ms + millis(self.config.cooldown) > time_ms)
+    }
+
+    fn first_drip(&mut self, sample: &AnalysisSample) -> Option<ShotEventKind> {
+        let dripped = sample
+            .measured_weight
+            .is_some_and(|weight| weight >= self.config.first_drip_weight);
+        if self.first_drip_ms.is_some() || !dripped {
+            return None;
+        }
+        self.first_drip_ms = Some(sample.time_ms);
+        if sample.time_ms < millis(self.config.min_first_drip) {
+            Some(ShotEventKind::FastFirstDrip)
+        } else {
+            None
+        }
+    }
+
+    fn pressure_drop(&self, sample: &AnalysisSample) -> Option<ShotEventKind> {
+        if self.cooling_down(ShotEventKind::PressureDrop, sample.time_ms) {
+            return None;
+        }
+        let from = sample
+            .time_ms
+            .saturating_sub(millis(self.config.drop_window));
+        let recent = self.window.iter().filter(|recent| recent.time_ms >= from);
+        let (max_pressure, min_flow) = recent.fold((f32::MIN, f32::MAX), |(pressure, flow), s| {
+            (pressure.max(s.pressure), flow.min(s.flow))
+        });
+        let dropped = max_pressure >= self.config.min_brew_pressure
+            && max_pressure - sample.pressure >= self.config.drop_pressure;
+        if dropped && sample.flow - min_flow >= self.config.spike_flow {
+            Some(ShotEventKind::PressureDrop)
+        } else {
+            None
+        }
+    }
+
+    fn flow_rise(&self, sample: &AnalysisSample) -> Option<ShotEventKind> {
+        if self.cooling_down(ShotEventKind::FlowRise, sample.time_ms) {
+            return None;
+        }
+        let rise_window = millis(self.config.rise_window);
+        // Only once the window covers the whole rise time.
+        let oldest = self.window.front()?;
+        if oldest.time_ms + rise_window > sample.time_ms {
+            return None;
+        }
+        let from = sample.time_ms - rise_window;
+        let start = self.window.iter().find(|s| s.time_ms >= from)?;
+        let steady = self.window.iter().filter(|s| s.time_ms >= from).all(|s| {
+            s.pressure >= self.config.min_brew_pressure
+                && (s.pressure - start.pressure).abs() <= self.config.steady_pressure
+        });
+        if steady && sample.flow - start.flow >= self.config.flow_rise {
+            Some(ShotEventKind::FlowRise)
+        } else {
+            None
+        }
+    }
+
+    // Runs the end of shot checks and returns every event of the shot.
+    pub fn finish(mut self, outcome: &ShotOutcome) -> Vec<ShotEvent> {
+        let last = self.window.back().copied();
+        let event = |kind| ShotEvent {
+            time_ms: millis(outcome.duration),
+            kind,
+            pressure: last.map(|s| s.pressure).unwrap_or(0.0),
+            flow: last.map(|s| s.flow).unwrap_or(0.0),
+        };
+        if let Some(target_weight) = outcome.target_weight {
+            if outcome.final_weight >= target_weight && outcome.duration < self.config.gusher_time {
+                self.events.push(event(ShotEventKind::Gusher));
+            }
+            if outcome.duration >= self.config.choker_time
+                && outcome.final_weight < target_weight * self.config.choker_yield
+            {
+                self.events.push(event(ShotEventKind::Choker));
+            }
+        }
+        self.events
+    }
+}
+
+pub fn analyse_samples(
+    samples: &[AnalysisSample],
+    outcome: &ShotOutcome,
+    config: &AnalysisConfig,
+) -> Vec<ShotEvent> {
+    let mut analyser = ShotAnalyser::new(config.clone());
+    for sample in samples {
+        analyser.push(*sample);
+    }
+    analyser.finish(outcome)
+}
+
+// The parts of a stored shot record the detectors need.
+#[derive(Deserialize)]
+struct RecordedSeries {
+    time_ms: Vec<u32>,
+    pressure: Vec<f32>,
+    flow: Vec<f32>,
+    weight: Vec<f32>,
+    #[serde(default)]
+    measured_weight: Vec<Option<f32>>,
+}
+
+#[derive(Deserialize)]
+struct RecordedShot {
+    duration: Duration,
+    final_weight: f32,
+    target_weight: Option<f32>,
+    samples: RecordedSeries,
+}
+
+// Runs the detectors again on a shot record as stored (or exported with format "record").
+pub fn analyse_record(
+    record: &serde_json::Value,
+    config: &AnalysisConfig,
+) -> Result<Vec<ShotEvent>> {
+    let shot = RecordedShot::deserialize(record)?;
+    let series = &shot.samples;
+    let samples: Vec<AnalysisSample> = (0..series.time_ms.len())
+        .map(|index| AnalysisSample {
+            time_ms: series.time_ms[index],
+            pressure: series.pressure.get(index).copied().unwrap_or(0.0),
+            flow: series.flow.get(index).copied().unwrap_or(0.0),
+            weight: series.weight.get(index).copied().unwrap_or(0.0),
+            measured_weight: series.measured_weight.get(index).copied().flatten(),
+        })
+        .collect();
+    let outcome = ShotOutcome {
+        duration: shot.duration,
+        final_weight: shot.final_weight,
+        target_weight: shot.target_weight,
+    };
+    Ok(analyse_samples(&samples, &outcome, config))
+}
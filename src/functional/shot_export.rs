@@ -0,0 +1,276 @@
+use std::{io::Write, time::Duration};
+
+use anyhow::Result;
+use serde::{ser::SerializeSeq, Deserialize, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::functional::shot_series::ShotSeries;
+
+// Exports of recorded shots. They are written straight from the `ShotSeries` columns of the
+// record into the transfer, without building the export in memory first. The snapshots of
+// the shot are gone by the time it is exported and the record only keeps these columns, the
+// host converter reads records back into the same columns to run the same code.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    // The record as stored.
+    Record,
+    // Decent style json shot file, as uploaded to visualizer.coffee.
+    Visualizer,
+    Csv,
+}
+
+// The parts of `ShotRecord` the exports need, borrowed from it. `record` is written as is
+// for the record format.
+pub struct ExportedShot<'a, R: Serialize> {
+    pub record: &'a R,
+    pub id: u32,
+    pub start_time: u64,
+    pub profile: Option<&'a str>,
+    pub dose: Option<f32>,
+    pub target_weight: Option<f32>,
+    pub final_weight: f32,
+    pub duration: Duration,
+    pub peak_pressure: f32,
+    pub stop_reason: Value,
+    pub samples: &'a ShotSeries,
+}
+
+// The record with its place in the ring, which isn't stored in the record itself.
+#[derive(Serialize)]
+struct StoredRecord<'a, R: Serialize> {
+    id: u32,
+    #[serde(flatten)]
+    record: &'a R,
+}
+
+// Seconds since the start of the shot.
+struct Elapsed<'a>(&'a [u32]);
+
+impl Serialize for Elapsed<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for time in self.0 {
+            seq.serialize_element(&seconds(*time))?;
+        }
+        seq.end()
+    }
+}
+
+fn seconds(time_ms: u32) -> f64 {
+    time_ms as f64 / 1000.0
+}
+
+// Flow into the cup from the change in weight, visualizer plots it next to the pump flow.
+fn weight_flow(time_ms: &[u32], weight: &[f32]) -> Vec<f32> {
+    let mut flow = Vec::with_capacity(weight.len());
+    for index in 0..weight.len().min(time_ms.len()) {
+        let value = match index.checked_sub(1) {
+            Some(previous) if time_ms[index] > time_ms[previous] => {
+                let elapsed = (seconds(time_ms[index]) - seconds(time_ms[previous])) as f32;
+                ((weight[index] - weight[previous]) / elapsed).max(0.0)
+            }
+            _ => 0.0,
+        };
+        flow.push(value);
+    }
+    flow
+}
+
+// The visualizer file, fields in the order the file has always had them.
+#[derive(Serialize)]
+struct VisualizerShot<'a> {
+    app: VisualizerApp<'a>,
+    clock: String,
+    elapsed: Elapsed<'a>,
+    flow: VisualizerFlow<'a>,
+    meta: VisualizerMeta,
+    pressure: VisualizerPressure<'a>,
+    profile: VisualizerProfile<'a>,
+    temperature: VisualizerTemperature<'a>,
+    timestamp: String,
+    totals: VisualizerTotals<'a>,
+    version: u32,
+}
+
+#[derive(Serialize)]
+struct VisualizerApp<'a> {
+    app_name: &'a str,
+    data: VisualizerData<'a>,
+}
+
+#[derive(Serialize)]
+struct VisualizerData<'a> {
+    settings: VisualizerSettings<'a>,
+}
+
+#[derive(Serialize)]
+struct VisualizerSettings<'a> {
+    peak_pressure: f32,
+    stop_reason: &'a Value,
+    target_weight: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct VisualizerFlow<'a> {
+    by_weight: Vec<f32>,
+    flow: &'a [f32],
+}
+
+#[derive(Serialize)]
+struct VisualizerMeta {
+    #[serde(rename = "in")]
+    dose: Option<f32>,
+    #[serde(rename = "out")]
+    final_weight: f32,
+    time: f64,
+}
+
+#[derive(Serialize)]
+struct VisualizerPressure<'a> {
+    pressure: &'a [f32],
+}
+
+#[derive(Serialize)]
+struct VisualizerProfile<'a> {
+    title: &'a str,
+}
+
+#[derive(Serialize)]
+struct VisualizerTemperature<'a> {
+    basket: &'a [f32],
+    mix: &'a [f32],
+}
+
+#[derive(Serialize)]
+struct VisualizerTotals<'a> {
+    weight: &'a [f32],
+}
+
+pub fn write_visualizer<R: Serialize>(shot: &ExportedShot<R>, out: &mut dyn Write) -> Result<()> {
+    let samples = shot.samples;
+    let visualizer = VisualizerShot {
+        app: VisualizerApp {
+            app_name: "anitta",
+            data: VisualizerData {
+                settings: VisualizerSettings {
+                    peak_pressure: shot.peak_pressure,
+                    stop_reason: &shot.stop_reason,
+                    target_weight: shot.target_weight,
+                },
+            },
+        },
+        clock: shot.start_time.to_string(),
+        elapsed: Elapsed(&samples.time_ms),
+        flow: VisualizerFlow {
+            by_weight: weight_flow(&samples.time_ms, &samples.weight),
+            flow: &samples.flow,
+        },
+        meta: VisualizerMeta {
+            dose: shot.dose,
+            final_weight: shot.final_weight,
+            time: shot.duration.as_secs_f64(),
+        },
+        pressure: VisualizerPressure {
+            pressure: &samples.pressure,
+        },
+        profile: VisualizerProfile {
+            title: shot.profile.unwrap_or("Anitta programmed shot"),
+        },
+        temperature: VisualizerTemperature {
+            basket: &samples.temperature,
+            mix: &samples.temperature,
+        },
+        timestamp: shot.start_time.to_string(),
+        totals: VisualizerTotals {
+            weight: &samples.weight,
+        },
+        version: 2,
+    };
+    Ok(serde_json::to_writer(out, &visualizer)?)
+}
+
+fn csv_field<T: Serialize>(value: &T) -> Result<String> {
+    Ok(match serde_json::to_value(value)? {
+        Value::Null => String::new(),
+        Value::String(text) if text.contains([',', '"', '\n']) => {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        }
+        Value::String(text) => text,
+        // Written from the value itself, a json number holds an f32 as the closest f64.
+        _ => serde_json::to_string(value)?,
+    })
+}
+
+// A csv column, the series are left out of the file when the record has none of them.
+struct CsvColumn<'a> {
+    header: &'static str,
+    field: Box<dyn Fn(usize) -> Result<String> + 'a>,
+    recorded: bool,
+}
+
+fn csv_column<'a, T: Serialize>(header: &'static str, series: &'a [T]) -> CsvColumn<'a> {
+    CsvColumn {
+        header,
+        field: Box::new(move |row| match series.get(row) {
+            Some(value) => csv_field(value),
+            None => Ok(String::new()),
+        }),
+        recorded: !series.is_empty(),
+    }
+}
+
+// One row per sample and one column per series, time in seconds.
+pub fn write_csv<R: Serialize>(shot: &ExportedShot<R>, out: &mut dyn Write) -> Result<()> {
+    let samples = shot.samples;
+    let columns: Vec<CsvColumn> = [
+        CsvColumn {
+            header: "elapsed_s",
+            field: Box::new(|row| csv_field(&seconds(samples.time_ms[row]))),
+            recorded: true,
+        },
+        csv_column("pressure_bar", &samples.pressure),
+        csv_column("flow_ml_s", &samples.flow),
+        csv_column("pump_flow_ml_s", &samples.pump_flow),
+        csv_column("weight_g", &samples.weight),
+        csv_column("measured_weight_g", &samples.measured_weight),
+        csv_column("temperature_c", &samples.temperature),
+        csv_column("puck_resistance", &samples.puck_resistance),
+        csv_column("profile_phase", &samples.profile_phase),
+        csv_column("shot_stage", &samples.shot_stage),
+        csv_column("target_pressure", &samples.target_pressure),
+    ]
+    .into_iter()
+    .filter(|column| column.recorded)
+    .collect();
+
+    let headers: Vec<&str> = columns.iter().map(|column| column.header).collect();
+    writeln!(out, "{}", headers.join(","))?;
+    for row in 0..samples.len() {
+        let fields = columns
+            .iter()
+            .map(|column| (column.field)(row))
+            .collect::<Result<Vec<_>>>()?;
+        writeln!(out, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
+
+pub fn export_shot<R: Serialize>(
+    shot: &ExportedShot<R>,
+    format: ExportFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    match format {
+        ExportFormat::Record => {
+            let record = StoredRecord {
+                id: shot.id,
+                record: shot.record,
+            };
+            Ok(serde_json::to_writer(out, &record)?)
+        }
+        ExportFormat::Visualizer => write_visualizer(shot, out),
+        ExportFormat::Csv => write_csv(shot, out),
+    }
+}
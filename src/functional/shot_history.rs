@@ -0,0 +1,266 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actuators::three_way_valve::ValveState,
+    functional::{
+        espresso::{ShotResult, ShotStopReason},
+        espresso_state::EspressoStateSnapshot,
+        handoff::SHOT_EVENTS,
+        predictive_stop::get_drip_compensation,
+        shot_analysis::{AnalysisConfig, AnalysisSample, ShotAnalyser, ShotEvent, ShotOutcome},
+        shot_export::ExportedShot,
+        shot_series::{ShotSeries, ShotStage},
+        shot_summary::{ShotSummary, ShotSummaryBuilder, SummaryOutcome, SummarySample},
+    },
+    storage::flash_ring::FlashRing,
+};
+
+// Every shot is kept on the `shots` partition with its time series, the oldest shots are
+// evicted once the partition is full.
+
+const HISTORY_PARTITION: &str = "shots";
+// Samples are kept at this rate, when a shot runs long enough to fill MAX_SAMPLES every
+// other sample is dropped and the period doubled.
+const SAMPLE_PERIOD: Duration = Duration::from_millis(250);
+const MAX_SAMPLES: usize = 480;
+
+static SHOT_HISTORY: OnceCell<Mutex<FlashRing>> = OnceCell::new();
+
+fn push_sample(samples: &mut ShotSeries, shot_time: Duration, snapshot: &EspressoStateSnapshot) {
+    samples.time_ms.push(shot_time.as_millis() as u32);
+    samples.pressure.push(snapshot.pressure);
+    samples.flow.push(snapshot.estimated_espresso_flow);
+    samples.pump_flow.push(snapshot.pump_flow);
+    samples.weight.push(snapshot.estimated_weight);
+    samples.measured_weight.push(snapshot.measured_weight);
+    samples.temperature.push(snapshot.boiler_temp);
+    samples
+        .profile_phase
+        .push(snapshot.profile_phase.map(|phase| phase as u8));
+    samples.shot_stage.push(snapshot.shot_stage);
+    samples.puck_resistance.push(snapshot.puck_resistance);
+    samples.target_pressure.push(snapshot.target_pressure);
+}
+
+// The valve state from `time_ms` into the shot on.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ValveEvent {
+    pub time_ms: u32,
+    pub state: ValveState,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShotRecord {
+    // Sequence number in the store, set when the record is read back.
+    #[serde(default, skip_serializing)]
+    pub id: u32,
+    // Seconds since the unix epoch.
+    pub start_time: u64,
+    pub profile: Option<String>,
+    pub dose: Option<f32>,
+    pub target_weight: Option<f32>,
+    pub final_weight: f32,
+    pub duration: Duration,
+    pub peak_pressure: f32,
+    // Mean boiler temperature over the shot.
+    pub temperature: f32,
+    pub stop_reason: ShotStopReason,
+    pub samples: ShotSeries,
+    // Anomalies found while the shot ran and once it ended.
+    #[serde(default)]
+    pub events: Vec<ShotEvent>,
+    // The state at the first sample, then every toggle, kept apart from the samples so a
+    // toggle between two of them isn't lost.
+    #[serde(default)]
+    pub valve_events: Vec<ValveEvent>,
+    #[serde(default)]
+    pub summary: Option<ShotSummary>,
+}
+
+impl ShotRecord {
+    pub fn exported(&self) -> Result<ExportedShot<'_, ShotRecord>> {
+        Ok(ExportedShot {
+            record: self,
+            id: self.id,
+            start_time: self.start_time,
+            profile: self.profile.as_deref(),
+            dose: self.dose,
+            target_weight: self.target_weight,
+            final_weight: self.final_weight,
+            duration: self.duration,
+            peak_pressure: self.peak_pressure,
+            stop_reason: serde_json::to_value(self.stop_reason)?,
+            samples: &self.samples,
+        })
+    }
+}
+
+pub struct ShotRecorder {
+    profile: Option<String>,
+    dose: Option<f32>,
+    samples: ShotSeries,
+    sample_period: Duration,
+    next_sample: Duration,
+    temperature_sum: f32,
+    temperature_count: u32,
+    // Fed every snapshot, not just the kept samples.
+    analyser: ShotAnalyser,
+    summary: ShotSummaryBuilder,
+    last_flow: f32,
+    valve_events: Vec<ValveEvent>,
+    valve_transitions: Option<u32>,
+}
+
+impl ShotRecorder {
+    pub fn new(profile: Option<String>, dose: Option<f32>) -> ShotRecorder {
+        // Clears the events of the previous shot on the tablet.
+        SHOT_EVENTS.publish(Vec::new());
+        let analysis_config = AnalysisConfig::default();
+        ShotRecorder {
+            profile,
+            dose,
+            samples: ShotSeries::default(),
+            sample_period: SAMPLE_PERIOD,
+            next_sample: Duration::ZERO,
+            temperature_sum: 0.0,
+            temperature_count: 0,
+            summary: ShotSummaryBuilder::new(analysis_config.first_drip_weight),
+            analyser: ShotAnalyser::new(analysis_config),
+            last_flow: 0.0,
+            valve_events: Vec::new(),
+            valve_transitions: None,
+        }
+    }
+
+    pub fn record(&mut self, shot_time: Duration, snapshot: &EspressoStateSnapshot) {
+        self.temperature_sum += snapshot.boiler_temp;
+        self.temperature_count += 1;
+        let sample = AnalysisSample {
+            time_ms: shot_time.as_millis() as u32,
+            pressure: snapshot.pressure,
+            flow: snapshot.estimated_espresso_flow,
+            weight: snapshot.estimated_weight,
+            measured_weight: snapshot.measured_weight,
+        };
+        if let Some(event) = self.analyser.push(sample) {
+            log::warn!("Shot event {:?}", event);
+            SHOT_EVENTS.publish(self.analyser.events().to_vec());
+        }
+        self.summary.push(&SummarySample {
+            time: shot_time,
+            pressure: snapshot.pressure,
+            flow: snapshot.estimated_espresso_flow,
+            weight: snapshot.estimated_weight,
+            measured_weight: snapshot.measured_weight,
+            temperature: snapshot.boiler_temp,
+            puck_resistance: snapshot.puck_resistance,
+            preinfusing: matches!(
+                snapshot.shot_stage,
+                Some(ShotStage::Preinfusion | ShotStage::Bloom)
+            ),
+        });
+        self.last_flow = snapshot.estimated_espresso_flow;
+        if self.valve_transitions != Some(snapshot.valve_transitions) {
+            self.valve_transitions = Some(snapshot.valve_transitions);
+            self.valve_events.push(ValveEvent {
+                time_ms: shot_time.as_millis() as u32,
+                state: snapshot.valve_state,
+            });
+        }
+        if shot_time < self.next_sample {
+            return;
+        }
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.decimate();
+            self.sample_period *= 2;
+        }
+        self.next_sample = shot_time + self.sample_period;
+        push_sample(&mut self.samples, shot_time, snapshot);
+    }
+
+    pub fn finish(self, result: &ShotResult) -> ShotRecord {
+        let temperature = if self.temperature_count > 0 {
+            self.temperature_sum / self.temperature_count as f32
+        } else {
+            0.0
+        };
+        let events = self.analyser.finish(&ShotOutcome {
+            duration: result.duration,
+            final_weight: result.final_weight,
+            target_weight: result.target_weight,
+        });
+        let summary = self.summary.finish(&SummaryOutcome {
+            duration: result.duration,
+            final_weight: result.final_weight,
+            estimated_weight: get_drip_compensation()
+                .predict_final_weight(result.final_weight, self.last_flow),
+            dose: self.dose,
+        });
+        ShotRecord {
+            id: 0,
+            start_time: result
+                .start_time
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            profile: self.profile,
+            dose: self.dose,
+            target_weight: result.target_weight,
+            final_weight: result.final_weight,
+            duration: result.duration,
+            peak_pressure: result.peak_pressure,
+            temperature,
+            stop_reason: result.stop_reason,
+            samples: self.samples,
+            events,
+            valve_events: self.valve_events,
+            summary: Some(summary),
+        }
+    }
+}
+
+pub fn init_shot_history() -> Result<()> {
+    let ring = FlashRing::open(HISTORY_PARTITION)?;
+    log::info!("Shot history holds {} shots", ring.sequences().len());
+    SHOT_HISTORY.set(Mutex::new(ring)).ok();
+    Ok(())
+}
+
+fn shot_history() -> Result<&'static Mutex<FlashRing>> {
+    SHOT_HISTORY
+        .get()
+        .ok_or_else(|| anyhow!("Shot history is not initialized"))
+}
+
+pub fn save_shot(record: &ShotRecord) -> Result<u32> {
+    let data = serde_json::to_vec(record)?;
+    shot_history()?
+        .lock()
+        .expect("Failed to acquire lock")
+        .append(&data)
+}
+
+// Ids of the stored shots, oldest first.
+pub fn get_shot_ids() -> Vec<u32> {
+    match shot_history() {
+        Ok(ring) => ring.lock().expect("Failed to acquire lock").sequences(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn get_shot(id: u32) -> Result<ShotRecord> {
+    let data = shot_history()?
+        .lock()
+        .expect("Failed to acquire lock")
+        .read_record(id)?;
+    let mut record: ShotRecord = serde_json::from_slice(&data)?;
+    record.id = id;
+    Ok(record)
+}
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+
+// The samples a shot record keeps, apart from the recorder so the host converter can read
+// records back into the same columns the firmware exports them from.
+
+// Stage of a programmed shot, recorded on every snapshot so transitions show up in telemetry.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShotStage {
+    Preinfusion,
+    Bloom,
+    Ramp,
+    Brew,
+}
+
+// The time series of a shot, one vector per measurement so a record stays compact and each
+// series can be handed to a chart or an export as is.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShotSeries {
+    pub time_ms: Vec<u32>,
+    pub pressure: Vec<f32>,
+    pub flow: Vec<f32>,
+    pub pump_flow: Vec<f32>,
+    pub weight: Vec<f32>,
+    // None while the weight comes from the pump model.
+    #[serde(default)]
+    pub measured_weight: Vec<Option<f32>>,
+    pub temperature: Vec<f32>,
+    pub profile_phase: Vec<Option<u8>>,
+    pub shot_stage: Vec<Option<ShotStage>>,
+    #[serde(default)]
+    pub puck_resistance: Vec<Option<f32>>,
+    #[serde(default)]
+    pub target_pressure: Vec<Option<f32>>,
+}
+
+fn keep_every_other<T>(values: &mut Vec<T>) {
+    let mut index = 0;
+    values.retain(|_| {
+        index += 1;
+        index % 2 == 1
+    });
+}
+
+impl ShotSeries {
+    pub fn len(&self) -> usize {
+        self.time_ms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.time_ms.is_empty()
+    }
+
+    pub fn decimate(&mut self) {
+        keep_every_other(&mut self.time_ms);
+        keep_every_other(&mut self.pressure);
+        keep_every_other(&mut self.flow);
+        keep_every_other(&mut self.pump_flow);
+        keep_every_other(&mut self.weight);
+        keep_every_other(&mut self.measured_weight);
+        keep_every_other(&mut self.temperature);
+        keep_every_other(&mut self.profile_phase);
+        keep_every_other(&mut self.shot_stage);
+        keep_every_other(&mut self.puck_resistance);
+        keep_every_other(&mut self.target_pressure);
+    }
+}
@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+// The summary card of a shot, accumulated from every control cycle rather than from the kept
+// samples so short events like the first drip aren't missed.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemperatureStability {
+    pub min: f32,
+    pub max: f32,
+    pub std_dev: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShotSummary {
+    pub total_time: Duration,
+    pub time_to_first_drip: Option<Duration>,
+    // Preinfusion and bloom, None for shots without a preinfusion stage.
+    pub preinfusion_time: Option<Duration>,
+    pub mean_pressure: f32,
+    pub peak_pressure: f32,
+    pub mean_flow: f32,
+    // Weight when the pump stopped, and with the drip still on its way to the cup.
+    pub final_yield: f32,
+    pub estimated_yield: f32,
+    // Estimated yield over the dose, e.g. 2.0 for 18g in and 36g out.
+    pub brew_ratio: Option<f32>,
+    pub temperature: Option<TemperatureStability>,
+    // Mean of the live puck resistance while there was enough flow to have one.
+    pub mean_puck_resistance: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SummarySample {
+    pub time: Duration,
+    pub pressure: f32,
+    pub flow: f32,
+    pub weight: f32,
+    // The first drip is only taken from the flow meters.
+    pub measured_weight: Option<f32>,
+    pub temperature: f32,
+    pub puck_resistance: Option<f32>,
+    pub preinfusing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SummaryOutcome {
+    pub duration: Duration,
+    pub final_weight: f32,
+    pub estimated_weight: f32,
+    pub dose: Option<f32>,
+}
+
+pub struct ShotSummaryBuilder {
+    first_drip_weight: f32,
+    samples: u32,
+    pressure_sum: f32,
+    peak_pressure: f32,
+    flow_sum: f32,
+    first_drip: Option<Duration>,
+    preinfusion_end: Option<Duration>,
+    saw_preinfusion: bool,
+    // Welford's running mean and variance, the shot isn't kept in memory.
+    temperature_mean: f32,
+    temperature_m2: f32,
+    temperature_min: f32,
+    temperature_max: f32,
+    resistance_sum: f32,
+    resistance_samples: u32,
+}
+
+impl ShotSummaryBuilder {
+    pub fn new(first_drip_weight: f32) -> ShotSummaryBuilder {
+        ShotSummaryBuilder {
+            first_drip_weight,
+            samples: 0,
+            pressure_sum: 0.0,
+            peak_pressure: 0.0,
+            flow_sum: 0.0,
+            first_drip: None,
+            preinfusion_end: None,
+            saw_preinfusion: false,
+            temperature_mean: 0.0,
+            temperature_m2: 0.0,
+            temperature_min: f32::MAX,
+            temperature_max: f32::MIN,
+            resistance_sum: 0.0,
+            resistance_samples: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: &SummarySample) {
+        self.samples += 1;
+        self.pressure_sum += sample.pressure;
+        self.peak_pressure = self.peak_pressure.max(sample.pressure);
+        self.flow_sum += sample.flow;
+
+        let dripped = sample
+            .measured_weight
+            .is_some_and(|weight| weight >= self.first_drip_weight);
+        if self.first_drip.is_none() && dripped {
+            self.first_drip = Some(sample.time);
+        }
+        if sample.preinfusing {
+            self.saw_preinfusion = true;
+        } else if self.saw_preinfusion && self.preinfusion_end.is_none() {
+            self.preinfusion_end = Some(sample.time);
+        }
+
+        let delta = sample.temperature - self.temperature_mean;
+        self.temperature_mean += delta / self.samples as f32;
+        self.temperature_m2 += delta * (sample.temperature - self.temperature_mean);
+        self.temperature_min = self.temperature_min.min(sample.temperature);
+        self.temperature_max = self.temperature_max.max(sample.temperature);
+
+        if let Some(resistance) = sample.puck_resistance {
+            self.resistance_sum += resistance;
+            self.resistance_samples += 1;
+        }
+    }
+
+    pub fn finish(self, outcome: &SummaryOutcome) -> ShotSummary {
+        let mean = |sum: f32, count: u32| {
+            if count > 0 {
+                sum / count as f32
+            } else {
+                0.0
+            }
+        };
+        let preinfusion_time = if self.saw_preinfusion {
+            Some(self.preinfusion_end.unwrap_or(outcome.duration))
+        } else {
+            None
+        };
+        let temperature = if self.samples > 0 {
+            Some(TemperatureStability {
+                min: self.temperature_min,
+                max: self.temperature_max,
+                std_dev: (self.temperature_m2 / self.samples as f32).sqrt(),
+            })
+        } else {
+            None
+        };
+        ShotSummary {
+            total_time: outcome.duration,
+            time_to_first_drip: self.first_drip,
+            preinfusion_time,
+            mean_pressure: mean(self.pressure_sum, self.samples),
+            peak_pressure: self.peak_pressure,
+            mean_flow: mean(self.flow_sum, self.samples),
+            final_yield: outcome.final_weight,
+            estimated_yield: outcome.estimated_weight,
+            brew_ratio: outcome
+                .dose
+                .filter(|dose| *dose > 0.0)
+                .map(|dose| outcome.estimated_weight / dose),
+            temperature,
+            mean_puck_resistance: if self.resistance_samples > 0 {
+                Some(mean(self.resistance_sum, self.resistance_samples))
+            } else {
+                None
+            },
+        }
+    }
+}
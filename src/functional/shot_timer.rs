@@ -0,0 +1,118 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::functional::espresso_state::EspressoStateSnapshot;
+
+// The timer of a manual shot. The switch only opens the valve and starts the pump, the shot
+// itself starts when the puck builds pressure or the first drips reach the cup, so the timer
+// can wait for either.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TimerStart {
+    Button,
+    Pressure,
+    // Needs the flow meters, the pump model can't tell when the cup sees the first drip.
+    FirstDrip,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShotTimerConfig {
+    pub start: TimerStart,
+    pub pressure_threshold: f32,
+    pub first_drip_weight: f32,
+}
+
+impl Default for ShotTimerConfig {
+    fn default() -> Self {
+        ShotTimerConfig {
+            start: TimerStart::Pressure,
+            pressure_threshold: 1.5,
+            first_drip_weight: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum TimerPhase {
+    // Pump on, the start trigger hasn't fired yet.
+    Waiting,
+    Running,
+    Stopped,
+}
+
+// What the `shot_timer` characteristic shows.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShotTimerState {
+    pub phase: TimerPhase,
+    pub start: TimerStart,
+    pub elapsed: Duration,
+    // Since the button press, includes the wait for the trigger.
+    pub since_button: Duration,
+}
+
+pub struct ShotTimer {
+    config: ShotTimerConfig,
+    pressed: Instant,
+    started: Option<Instant>,
+    stopped: Option<Instant>,
+}
+
+impl ShotTimer {
+    // Call it when the button is pressed.
+    pub fn new(config: ShotTimerConfig) -> ShotTimer {
+        let pressed = Instant::now();
+        ShotTimer {
+            started: (config.start == TimerStart::Button).then_some(pressed),
+            config,
+            pressed,
+            stopped: None,
+        }
+    }
+
+    // Checks the trigger against the latest snapshot, returns the shot time once running.
+    pub fn update(&mut self, snapshot: &EspressoStateSnapshot) -> Option<Duration> {
+        if self.started.is_none() {
+            let triggered = match self.config.start {
+                TimerStart::Button => true,
+                TimerStart::Pressure => snapshot.pressure >= self.config.pressure_threshold,
+                TimerStart::FirstDrip => snapshot
+                    .measured_weight
+                    .is_some_and(|weight| weight >= self.config.first_drip_weight),
+            };
+            if triggered {
+                log::info!(
+                    "Shot timer started by {:?} after {:?}",
+                    self.config.start,
+                    self.pressed.elapsed()
+                );
+                self.started = Some(Instant::now());
+            }
+        }
+        self.elapsed()
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        let end = self.stopped.unwrap_or_else(Instant::now);
+        self.started.map(|started| end.duration_since(started))
+    }
+
+    pub fn stop(&mut self) {
+        self.stopped.get_or_insert_with(Instant::now);
+    }
+
+    pub fn state(&self) -> ShotTimerState {
+        let phase = match (self.started, self.stopped) {
+            (_, Some(_)) => TimerPhase::Stopped,
+            (Some(_), None) => TimerPhase::Running,
+            (None, None) => TimerPhase::Waiting,
+        };
+        let end = self.stopped.unwrap_or_else(Instant::now);
+        ShotTimerState {
+            phase,
+            start: self.config.start,
+            elapsed: self.elapsed().unwrap_or(Duration::ZERO),
+            since_button: end.duration_since(self.pressed),
+        }
+    }
+}
@@ -1,12 +1,14 @@
 use anyhow::{bail, Result};
 use board::board::Board;
 use coffee_machine::config::CoffeeMachineConfig;
+use connectivity::commands::MachineCommand;
 use esp32_nimble::{uuid128, NimbleProperties};
 use esp_idf_hal::gpio::Gpio2;
 use esp_idf_hal::gpio::PinDriver;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_sys as _;
 use functional::espresso_state::EspressoStateSnapshot;
+use functional::flush::ButtonPress;
 use sensors::temperature;
 use serde::Deserialize;
 use serde::Serialize;
@@ -23,6 +25,7 @@ use std::time::Duration;
 
 mod sensors {
     pub mod flow;
+    pub mod paddle;
     pub mod pressure;
     pub mod temperature;
 }
@@ -33,36 +36,76 @@ mod board {
 
 mod coffee_machine {
     pub mod config;
+    pub mod water;
 }
 
 mod connectivity {
     pub mod bt;
+    pub mod commands;
+    pub mod publisher;
+    pub mod transfer;
     // pub mod wifi;
 }
 
 mod functional {
+    pub mod backflush;
+    pub mod control_loop;
+    pub mod descale;
+    pub mod drip_compensation;
     pub mod espresso;
     pub mod espresso_state;
+    pub mod flush;
+    pub mod handoff;
+    pub mod hot_water;
+    pub mod paddle;
+    pub mod predictive_stop;
+    pub mod presets;
+    pub mod program_stop;
+    pub mod profile;
+    pub mod puck_resistance;
+    pub mod replay;
+    pub mod replay_fit;
+    pub mod shot_analysis;
+    pub mod shot_export;
+    pub mod shot_history;
+    pub mod shot_series;
+    pub mod shot_summary;
+    pub mod shot_timer;
+}
+
+mod storage {
+    pub mod flash_ring;
+    pub mod nvs;
+}
+
+mod profile_import {
+    pub mod decent;
+    pub mod gaggiuino;
+    pub mod report;
+    pub mod upload;
 }
 
 mod actuators {
     pub mod boiler;
+    pub mod controller_config;
+    pub mod pressure_controller;
     pub mod psm;
     pub mod pump;
+    pub mod pump_duty;
+    pub mod pump_protection;
+    pub mod watchdog;
 }
 
 use log::info;
 use once_cell::sync::OnceCell;
 
-static BOARD: OnceCell<Arc<Mutex<Board<'static>>>> = OnceCell::new();
 static ESPRESSO_SYSTEM_STACK: OnceCell<
     Mutex<Vec<functional::espresso_state::EspressoStateSnapshot>>,
 > = OnceCell::new();
 static MACHINE_CONFIG: OnceCell<Arc<Mutex<CoffeeMachineConfig>>> = OnceCell::new();
 
-pub fn init_board() {
-    let board = Board::<'static>::init().unwrap();
-    BOARD.set(Arc::new(Mutex::new(board))).unwrap();
+pub fn init_board() -> Board<'static> {
+    Board::<'static>::init().unwrap()
 }
 
 pub fn init_espresso_memory_stack() {
@@ -83,18 +126,102 @@ fn init_bluetooth(
     let snapshot_service =
         board.set_ble_service(uuid128!("02550882-1f94-4b1e-a448-e2f7691ac386"), "snapshot");
     board.set_ble_characteristic(
-        snapshot_service,
+        snapshot_service.clone(),
         uuid128!("799f64c7-362b-44b9-9413-2d2ee8e5a784"),
         "snapshot_state",
         NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::INDICATE,
         b"initializing snapshot no measures yet",
     );
+    board.set_ble_characteristic(
+        snapshot_service.clone(),
+        uuid128!("3e9d7b15-84c2-4a6f-b0d8-1f5a2c7e9b43"),
+        "last_shot",
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::INDICATE,
+        b"no shot yet",
+    );
+    let shot_ids = serde_json::to_string(&functional::shot_history::get_shot_ids())
+        .unwrap_or_default();
+    board.set_ble_characteristic(
+        snapshot_service.clone(),
+        uuid128!("b5d2e8f1-7a3c-4d6e-9f0b-1c4e7a2d5b86"),
+        "shot_history",
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+        shot_ids.as_bytes(),
+    );
+    let shot_export = board.set_ble_characteristic(
+        snapshot_service.clone(),
+        uuid128!("d1f4a7c2-5e8b-4b3d-a6c9-0e2f5b8d1a47"),
+        "shot_export",
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+        b"",
+    );
+    // The next chunk of an export only goes out once nimble has sent this one.
+    shot_export
+        .lock()
+        .on_notify_tx(|_| connectivity::transfer::chunk_sent());
+    board.set_ble_characteristic(
+        snapshot_service.clone(),
+        uuid128!("0b3d5f7a-9c2e-4a6b-8d1f-5e7a9c0b2d64"),
+        "shot_events",
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+        b"[]",
+    );
+    board.set_ble_characteristic(
+        snapshot_service.clone(),
+        uuid128!("e3a5c7e9-0b2d-4f4e-8a6c-1d3f5b7d9e02"),
+        "shot_timer",
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+        b"no shot yet",
+    );
+    // The tablet's slider for paddle shots, written as often as it moves.
+    let paddle = board.set_ble_characteristic(
+        snapshot_service.clone(),
+        uuid128!("4f6b8d0e-2a3c-4e5f-9b7d-0c1e3a5f7b92"),
+        "paddle",
+        NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP,
+        b"0",
+    );
+    paddle.lock().on_write(move |val| {
+        if let Err(e) = functional::paddle::set_ble_target(val.recv_data()) {
+            log::error!("Invalid paddle pressure: {:?}", e);
+        }
+    });
+    board.set_ble_characteristic(
+        snapshot_service.clone(),
+        uuid128!("6d8f0b2c-4e7a-4c9d-a1b3-7f9e2d4c6a85"),
+        "shot_summary",
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+        b"no shot yet",
+    );
+    let control_loop_config =
+        serde_json::json!({ "config": functional::control_loop::get_control_loop_config() });
+    let control_loop = board.set_ble_characteristic(
+        snapshot_service,
+        uuid128!("f7a2c4e6-8b1d-4f3a-9c5e-2d7b0a4f6c93"),
+        "control_loop",
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::WRITE,
+        control_loop_config.to_string().as_bytes(),
+    );
+    control_loop.lock().on_write(move |val| {
+        let config: Result<functional::control_loop::ControlLoopConfig, serde_json::Error> =
+            serde_json::from_slice(val.recv_data());
+        match config {
+            Ok(config) => {
+                if let Err(e) = functional::control_loop::set_control_loop_config(config) {
+                    log::error!("Failed to set the control loop config: {:?}", e);
+                }
+            }
+            Err(e) => log::error!("Failed to deserialize control loop config: {:?}", e),
+        }
+    });
 
     let config_service = board.set_ble_service(
         uuid128!("497b30e0-c4be-4bca-8a38-cc74e84cd4ce"),
         "configuration",
     );
-    board.set_ble_characteristic(
+    let shot_config = serde_json::to_string(&functional::espresso::get_shot_config())
+        .unwrap_or_default();
+    let shot_config_publisher = board.set_ble_characteristic(
         config_service.clone(),
         uuid128!("e0caff18-2598-4bac-86f7-50b06c3478a6"),
         "shot_config",
@@ -102,10 +229,16 @@ fn init_bluetooth(
             | NimbleProperties::NOTIFY
             | NimbleProperties::INDICATE
             | NimbleProperties::WRITE,
-        b"initializing configuration",
+        shot_config.as_bytes(),
     );
+    shot_config_publisher.lock().on_write(move |val| {
+        match functional::espresso::update_shot_config(val.recv_data()) {
+            Ok(shot_config) => log::info!("Updated shot config {:?}", shot_config),
+            Err(e) => log::error!("Failed to update shot config: {:?}", e),
+        }
+    });
     let machine_config_publisher = board.set_ble_characteristic(
-        config_service,
+        config_service.clone(),
         uuid128!("35124b97-6292-4c46-ae49-21171df21527"),
         "machine_configuration",
         NimbleProperties::WRITE
@@ -115,6 +248,159 @@ fn init_bluetooth(
         b"initializing configuration",
     );
 
+    let cleaning_stats = serde_json::to_string(&functional::backflush::get_cleaning_stats())
+        .unwrap_or_default();
+    board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("6f1c2a4e-3b7d-4f0a-9e52-8c1d0b7a5e31"),
+        "cleaning_stats",
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+        cleaning_stats.as_bytes(),
+    );
+    board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("a2d4f6e8-1c3b-4a5d-8e7f-9b0c1d2e3f40"),
+        "cleaning_progress",
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::INDICATE,
+        b"no cleaning running",
+    );
+    board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("5b8e2d71-6c4a-4f93-a1e0-7d3c9b2f8a64"),
+        "descale_progress",
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::INDICATE,
+        b"no descale running",
+    );
+    board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("c4e6a8b0-2d5f-4b1c-9a3e-6f8d0c2b4e71"),
+        "flush",
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+        b"no flush yet",
+    );
+    board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("8a0c2e4f-6b1d-4f3a-b5c7-d9e1f3a5b7c9"),
+        "hot_water_progress",
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::INDICATE,
+        b"no hot water running",
+    );
+    let water_report = serde_json::to_string(&coffee_machine::water::get_water_report())
+        .unwrap_or_default();
+    let water_stats = board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("8d3f1a6c-2e5b-4c7d-9a0e-4f6b8c2d1e35"),
+        "water_stats",
+        NimbleProperties::READ
+            | NimbleProperties::NOTIFY
+            | NimbleProperties::INDICATE
+            | NimbleProperties::WRITE,
+        water_report.as_bytes(),
+    );
+    water_stats.lock().on_write(move |val| {
+        let settings: Result<coffee_machine::water::WaterSettings, serde_json::Error> =
+            serde_json::from_slice(val.recv_data());
+        match settings {
+            Ok(settings) => {
+                if let Err(e) = coffee_machine::water::apply_settings(settings) {
+                    log::error!("Failed to apply water settings: {:?}", e);
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to deserialize water settings: {:?}", e);
+            }
+        }
+    });
+    let drip_compensation =
+        serde_json::to_string(&functional::predictive_stop::get_drip_compensation())
+            .unwrap_or_default();
+    let drip_compensation = board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("2c6e8a0d-4f1b-4d7e-b3a5-8e0c2f4a6d19"),
+        "drip_compensation",
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::WRITE,
+        drip_compensation.as_bytes(),
+    );
+    let pump_protection = serde_json::json!({
+        "config": actuators::pump_protection::get_pump_protection_config(),
+        "fault": actuators::pump_protection::get_pump_fault(),
+    });
+    let pump_protection = board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("9d1f3b5c-7e2a-4c6d-8f0b-3a5c7e9d1b26"),
+        "pump_protection",
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::WRITE,
+        pump_protection.to_string().as_bytes(),
+    );
+    pump_protection.lock().on_write(move |val| {
+        let config: Result<actuators::pump_duty::PumpProtectionConfig, serde_json::Error> =
+            serde_json::from_slice(val.recv_data());
+        match config {
+            Ok(config) => match actuators::pump_protection::set_pump_protection_config(config) {
+                Ok(()) => log::info!("Updated pump protection config"),
+                Err(e) => log::error!("Failed to set the pump protection config: {:?}", e),
+            },
+            Err(e) => log::error!("Failed to deserialize pump protection config: {:?}", e),
+        }
+    });
+    drip_compensation.lock().on_write(move |val| {
+        let settings: Result<functional::predictive_stop::DripSettings, serde_json::Error> =
+            serde_json::from_slice(val.recv_data());
+        match settings {
+            Ok(settings) => match functional::predictive_stop::apply_drip_settings(settings) {
+                Ok(compensation) => log::info!("Updated drip compensation {:?}", compensation),
+                Err(e) => log::error!("Failed to apply drip settings: {:?}", e),
+            },
+            Err(e) => log::error!("Failed to deserialize drip settings: {:?}", e),
+        }
+    });
+    let presets =
+        serde_json::to_string(&functional::presets::get_preset_summaries()).unwrap_or_default();
+    board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("f2a6c8e0-4b1d-4e3f-a5c7-9d0b2e4f6a81"),
+        "presets",
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::INDICATE,
+        presets.as_bytes(),
+    );
+    let profile_upload = board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("4a7c9e1b-3d5f-4b8a-9c2e-6f0a8d4b2c17"),
+        "profile_upload",
+        NimbleProperties::WRITE,
+        b"",
+    );
+    profile_upload.lock().on_write(move |val| {
+        if let Err(e) = profile_import::upload::handle_upload_chunk(val.recv_data()) {
+            log::error!("Failed to receive profile upload: {:?}", e);
+        }
+    });
+    board.set_ble_characteristic(
+        config_service.clone(),
+        uuid128!("9e3b5d7f-1a2c-4e6b-8d0f-3c5a7e9b1d28"),
+        "profile_import",
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::INDICATE,
+        b"no profile imported",
+    );
+    let machine_command = board.set_ble_characteristic(
+        config_service,
+        uuid128!("c7e1b3d5-9f2a-4c6e-8b0d-2a4c6e8f0b1d"),
+        "machine_command",
+        NimbleProperties::WRITE,
+        b"",
+    );
+    machine_command.lock().on_write(move |val| {
+        match connectivity::commands::parse_command(val.recv_data()) {
+            Ok(command) => {
+                log::info!("Received command {:?}", command);
+                connectivity::commands::push_command(command);
+            }
+            Err(e) => {
+                log::error!("Failed to deserialize command: {:?}", e);
+            }
+        }
+    });
+
     let onboard_led_clone = onboard_led.clone();
     machine_config_publisher.lock().on_write(move |val| {
         let mut onboard_led = onboard_led_clone.lock().unwrap();
@@ -174,13 +460,145 @@ fn init_bluetooth(
     };
 }
 
+fn notify_presets(board: &Board) {
+    let presets =
+        serde_json::to_string(&functional::presets::get_preset_summaries()).unwrap_or_default();
+    board.notify_characteristic("presets", presets.as_bytes());
+}
+
+fn do_flush(board: &mut Board, config: &functional::flush::FlushConfig) {
+    match functional::flush::do_flush(board, config) {
+        Ok(report) => {
+            let report = serde_json::to_string(&report).unwrap_or_default();
+            board.notify_characteristic("flush", report.as_bytes());
+        }
+        Err(e) => {
+            actuators::pump::set_pump_off();
+            board.three_way_valve.force_open();
+            log::error!("Flush failed: {:?}", e);
+        }
+    }
+}
+
+// Commands queued from the `machine_command` characteristic while the machine is idle.
+fn handle_command(board: &mut Board, command: MachineCommand) {
+    match command {
+        MachineCommand::StartBackflush { config } => {
+            let config = config.unwrap_or_default();
+            match functional::backflush::do_backflush(board, &config) {
+                Ok(stats) => {
+                    let stats = serde_json::to_string(&stats).unwrap_or_default();
+                    board.notify_characteristic("cleaning_stats", stats.as_bytes());
+                }
+                Err(e) => log::error!("Backflush failed: {:?}", e),
+            }
+        }
+        MachineCommand::StartFlush { config } => {
+            let config = match config {
+                Some(config) => {
+                    functional::flush::save_flush_config(&config);
+                    config
+                }
+                None => functional::flush::get_flush_config(),
+            };
+            do_flush(board, &config);
+        }
+        MachineCommand::StartHotWater { config } => {
+            let config = config.unwrap_or_default();
+            if let Err(e) = functional::hot_water::do_hot_water(board, &config) {
+                actuators::pump::set_pump_off();
+                log::error!("Hot water failed: {:?}", e);
+            }
+        }
+        MachineCommand::StartShot => functional::espresso::do_espresso_from_ble(board),
+        MachineCommand::SelectMode { mode } => match functional::espresso::select_mode(mode) {
+            Ok(()) => log::info!("Shot mode {:?}", mode),
+            Err(e) => log::error!("Failed to select shot mode {:?}: {:?}", mode, e),
+        },
+        MachineCommand::SelectPreset { id } => match functional::presets::get_preset(&id) {
+            Some(preset) => match functional::espresso::apply_preset(&preset) {
+                Ok(shot_config) => {
+                    coffee_machine::config::set_brew_temperature(preset.temperature);
+                    let shot_config = serde_json::to_string(&shot_config).unwrap_or_default();
+                    board.notify_characteristic("shot_config", shot_config.as_bytes());
+                }
+                Err(e) => log::error!("Failed to apply preset {:?}: {:?}", id, e),
+            },
+            None => log::error!("Unknown preset {:?}", id),
+        },
+        MachineCommand::SavePreset { id, preset } => {
+            if let Err(e) = functional::presets::save_preset(id, preset) {
+                log::error!("Failed to save preset: {:?}", e);
+            }
+            notify_presets(board);
+        }
+        MachineCommand::DeletePreset { id } => {
+            if let Err(e) = functional::presets::delete_preset(&id) {
+                log::error!("Failed to delete preset: {:?}", e);
+            }
+            notify_presets(board);
+        }
+        MachineCommand::StartDescale { config } => {
+            let config = config.unwrap_or_default();
+            if let Err(e) = functional::descale::start_descale(board, config) {
+                log::error!("Descale failed: {:?}", e);
+            }
+        }
+        MachineCommand::SettledWeight { weight } => {
+            functional::predictive_stop::learn_settled_weight(board, weight)
+        }
+        MachineCommand::ImportProfile { format, data } => import_profile(board, format, &data),
+        command => log::info!("Ignoring command {:?} while idle", command),
+    }
+}
+
+fn import_profile(board: &Board, format: profile_import::upload::ProfileFormat, data: &[u8]) {
+    let result = match profile_import::upload::import_profile(format, data) {
+        Ok(imported) => {
+            for warning in &imported.report.warnings {
+                log::warn!("Profile import: {}", warning);
+            }
+            let (id, preset) = profile_import::upload::to_preset(&imported);
+            let error = functional::presets::save_preset(id.clone(), preset)
+                .err()
+                .map(|e| e.to_string());
+            notify_presets(board);
+            profile_import::upload::ImportResult {
+                id: if error.is_none() { Some(id) } else { None },
+                error,
+                report: imported.report,
+            }
+        }
+        Err(e) => profile_import::upload::ImportResult {
+            id: None,
+            error: Some(e.to_string()),
+            report: Default::default(),
+        },
+    };
+    if let Some(error) = &result.error {
+        log::error!("Failed to import profile: {}", error);
+    }
+    let result = serde_json::to_string(&result).unwrap_or_default();
+    board.notify_characteristic("profile_import", result.as_bytes());
+}
+
 fn main() -> Result<()> {
     let sysloop = EspSystemEventLoop::take().unwrap();
 
     // Configure Advertiser Data
     thread::sleep(Duration::from_secs(5));
     init_espresso_memory_stack();
-    init_board();
+    let mut board = init_board();
+    if let Err(e) = storage::nvs::init_nvs() {
+        log::error!("Failed to init nvs: {:?}", e);
+    }
+    actuators::pump_protection::init_pump_protection();
+    if let Err(e) = functional::shot_history::init_shot_history() {
+        log::error!("Failed to init shot history: {:?}", e);
+    }
+    coffee_machine::water::init_water_tracking();
+    functional::control_loop::init_control_loop();
+    functional::predictive_stop::init_drip_compensation();
 
     // Link patches required for ESP-IDF
     esp_idf_svc::sys::link_patches();
@@ -189,18 +607,17 @@ fn main() -> Result<()> {
     esp_idf_svc::log::EspLogger::initialize_default();
 
     log::info!("Hello, world!");
+    if let Some(record) = actuators::watchdog::get_watchdog_record() {
+        log::warn!("Actuator watchdog tripped before reboot: {:?}", record);
+    }
     log::info!("Connecting to WiFi");
 
     // let sysloop_clone = sysloop.clone(); // Clone the event loop
 
-    let board_clone_main = BOARD.get().expect("BOARD not initialized").clone();
-
     functional::espresso::init_espresso_config();
     log::info!("Connected, starting loop");
 
-    let mut board_main = board_clone_main.lock().unwrap();
-
-    init_machine_config(&mut board_main);
+    init_machine_config(&mut board);
     let machine_config = MACHINE_CONFIG
         .get()
         .expect("MACHINE_CONFIG not initialized")
@@ -209,11 +626,12 @@ fn main() -> Result<()> {
     //initializing maching configuration.
     let machine_lock = machine_config.lock().unwrap();
     let machine_config_string = serde_json::to_string(&*machine_lock).unwrap();
-    let mut onboard_led = board_main.onboard_led.clone();
+    drop(machine_lock);
+    let mut onboard_led = board.onboard_led.clone();
 
-    init_bluetooth(&mut board_main,&mut onboard_led);
+    init_bluetooth(&mut board,&mut onboard_led);
 
-    board_main
+    board
         .ble_characteristics
         .get("machine_configuration")
         .unwrap()
@@ -221,47 +639,45 @@ fn main() -> Result<()> {
         .set_value(machine_config_string.as_bytes())
         .notify();
 
-    // Main loop
+    let publisher = connectivity::publisher::Publisher::new(&board);
+
+    // The control task owns the board from here on, this task only publishes what it hands
+    // off, saves and exports.
     log::info!("Connected, starting loop");
+    functional::control_loop::spawn_control_task(move || run_machine(board))?;
+    publisher.run()
+}
 
+// The machine loop on the control task: shots, cleaning and ble commands while idle.
+fn run_machine(mut board: Board<'static>) {
+    if let Some(progress) = functional::descale::get_saved_progress() {
+        if let Err(e) = functional::descale::resume_descale(&mut board, progress) {
+            log::error!("Descale failed: {:?}", e);
+        }
+    }
     loop {
-        //
-        // let pressure = match sensors::pressure::read_pressure(&mut *board_main) {
-        //     Ok(pressure) => pressure,
-        //     Err(_) => 0.0,
-        // };
-
-        // let espresso_snapshot = EspressoStateSnapshot::get_state(&mut board_main).unwrap();
-        // functional::espresso_state::push_snapshot(espresso_snapshot.clone());
-        //
-        // let json_data = serde_json::to_string(&espresso_snapshot).unwrap();
-        //
-        // let json_bytes = json_data.as_bytes();
-        //
-        // board_main
-        //     .ble_characteristics
-        //     .get("snapshot_state")
-        //     .unwrap()
-        //     .lock()
-        //     .set_value(&json_bytes)
-        //     .notify();
-
-        // // Borrow button state immutably
-        let button_state = board_main.get_button_state();
-        if button_state {
-            functional::espresso::do_espresso(&mut *board_main);
+        actuators::watchdog::feed();
+
+        if board.get_button_state() {
+            match functional::flush::classify_press(&board) {
+                ButtonPress::Hold => functional::espresso::do_espresso(&mut board),
+                ButtonPress::DoublePress => {
+                    let config = functional::flush::get_flush_config();
+                    do_flush(&mut board, &config);
+                }
+                ButtonPress::Tap => {}
+            }
         }
 
-        if let Some(stack) = ESPRESSO_SYSTEM_STACK.get() {
-            let mut stack = stack.lock().expect("Failed to acquire lock");
-            if stack.len() > 2 {
-                stack.pop();
-                // eprintln!("Poped out from stack");
+        if let Some(command) = connectivity::commands::take_command() {
+            handle_command(&mut board, command);
+        }
+        if let Some(replay) = functional::handoff::REPLAY_PRESETS.take() {
+            if let Err(e) = functional::presets::save_preset(replay.id, replay.preset) {
+                log::error!("Failed to save replay preset: {:?}", e);
             }
-        } else {
-            eprintln!("ESPRESSO_SYSTEM_STACK is not initialized");
+            notify_presets(&board);
         }
-        // start_espresso_thread(board_clone_main.clone());
-        std::thread::sleep(Duration::from_millis(500));
+        std::thread::sleep(functional::control_loop::control_period());
     }
 }
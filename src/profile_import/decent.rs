@@ -0,0 +1,336 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    functional::profile::{ControlType, ExitCondition, Phase, Profile, TransitionCurve},
+    profile_import::report::{ImportReport, ImportedProfile},
+};
+
+// Decent DE1 v2 json profiles, as saved by the de1app. Most numbers are written as strings.
+// DE1 steps map one to one onto phases; what the firmware can't do is dropped with a warning
+// or mapped to the closest thing it can do and listed as an approximation.
+
+// Metadata the de1app writes that has no bearing on how the shot is pulled.
+const IGNORED_FIELDS: [&str; 10] = [
+    "author",
+    "notes",
+    "lang",
+    "hidden",
+    "reference_file",
+    "changes_since_last_espresso",
+    "legacy_profile_type",
+    "type",
+    "target_volume_count_start",
+    "id",
+];
+
+// Temperature steps closer than this to the profile temperature aren't reported.
+const TEMPERATURE_TOLERANCE: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(try_from = "NumberOrString")]
+pub struct DecentNumber(pub f32);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrString {
+    Number(f32),
+    String(String),
+}
+
+impl TryFrom<NumberOrString> for DecentNumber {
+    type Error = String;
+
+    fn try_from(value: NumberOrString) -> Result<Self, Self::Error> {
+        match value {
+            NumberOrString::Number(number) => Ok(DecentNumber(number)),
+            NumberOrString::String(string) => string
+                .trim()
+                .parse()
+                .map(DecentNumber)
+                .map_err(|_| format!("expected a number, got {:?}", string)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DecentProfile {
+    pub title: String,
+    #[serde(default)]
+    pub version: Option<DecentNumber>,
+    #[serde(default)]
+    pub beverage_type: Option<String>,
+    pub steps: Vec<DecentStep>,
+    #[serde(default)]
+    pub target_weight: DecentNumber,
+    #[serde(default)]
+    pub target_volume: DecentNumber,
+    #[serde(default)]
+    pub tank_temperature: DecentNumber,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecentPump {
+    Pressure,
+    Flow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecentTransition {
+    Fast,
+    Smooth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecentSensor {
+    Coffee,
+    Water,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DecentStep {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub pump: DecentPump,
+    #[serde(default)]
+    pub transition: Option<DecentTransition>,
+    #[serde(default)]
+    pub pressure: DecentNumber,
+    #[serde(default)]
+    pub flow: DecentNumber,
+    pub seconds: DecentNumber,
+    #[serde(default)]
+    pub volume: DecentNumber,
+    #[serde(default)]
+    pub weight: DecentNumber,
+    #[serde(default)]
+    pub temperature: Option<DecentNumber>,
+    #[serde(default)]
+    pub sensor: Option<DecentSensor>,
+    #[serde(default)]
+    pub exit: Option<DecentExit>,
+    #[serde(default)]
+    pub limiter: Option<DecentLimiter>,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecentExitType {
+    Pressure,
+    Flow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecentExitCondition {
+    Over,
+    Under,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DecentExit {
+    #[serde(rename = "type")]
+    pub exit_type: DecentExitType,
+    pub condition: DecentExitCondition,
+    pub value: DecentNumber,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DecentLimiter {
+    pub value: DecentNumber,
+    #[serde(default)]
+    pub range: DecentNumber,
+}
+
+fn import_step(
+    step: &DecentStep,
+    context: &str,
+    temperature: Option<f32>,
+    report: &mut ImportReport,
+) -> Phase {
+    report.warn_unknown(context, &step.unknown);
+
+    let (control, end) = match step.pump {
+        DecentPump::Pressure => (ControlType::Pressure, step.pressure.0),
+        DecentPump::Flow => (ControlType::Flow, step.flow.0),
+    };
+    let seconds = match Duration::try_from_secs_f32(step.seconds.0.max(0.0)) {
+        Ok(seconds) => seconds,
+        Err(_) => {
+            report.warn(format!(
+                "{}: step time of {}s is out of range, the step is skipped",
+                context, step.seconds.0
+            ));
+            Duration::ZERO
+        }
+    };
+
+    // A smooth DE1 step ramps from the previous target over the whole step, which is what a
+    // linear transition with no start value does here.
+    let (transition, transition_time) = match step.transition.unwrap_or(DecentTransition::Fast) {
+        DecentTransition::Fast => (TransitionCurve::Instant, Duration::ZERO),
+        DecentTransition::Smooth => (TransitionCurve::Linear, seconds),
+    };
+
+    let mut conditions = vec![ExitCondition::Time(seconds)];
+    if let Some(exit) = &step.exit {
+        conditions.push(match (exit.exit_type, exit.condition) {
+            (DecentExitType::Pressure, DecentExitCondition::Over) => {
+                ExitCondition::PressureAbove(exit.value.0)
+            }
+            (DecentExitType::Pressure, DecentExitCondition::Under) => {
+                ExitCondition::PressureBelow(exit.value.0)
+            }
+            (DecentExitType::Flow, DecentExitCondition::Over) => {
+                ExitCondition::FlowAbove(exit.value.0)
+            }
+            (DecentExitType::Flow, DecentExitCondition::Under) => {
+                ExitCondition::FlowBelow(exit.value.0)
+            }
+        });
+    }
+    if step.weight.0 > 0.0 {
+        conditions.push(ExitCondition::Weight(step.weight.0));
+    }
+    if step.volume.0 > 0.0 {
+        report.warn(format!(
+            "{}: volume limit of {}ml is not supported, dropped",
+            context, step.volume.0
+        ));
+    }
+
+    // The DE1 limiter eases off over `range`, the pump here clamps at the limit.
+    let restriction = match &step.limiter {
+        Some(limiter) if limiter.value.0 > 0.0 => {
+            if limiter.range.0 > 0.0 {
+                report.approximate(format!(
+                    "{}: limiter range of {} ignored, {} is used as a hard limit",
+                    context, limiter.range.0, limiter.value.0
+                ));
+            }
+            limiter.value.0
+        }
+        _ => 0.0,
+    };
+
+    if let (Some(step_temperature), Some(temperature)) = (step.temperature, temperature) {
+        if (step_temperature.0 - temperature).abs() > TEMPERATURE_TOLERANCE {
+            report.approximate(format!(
+                "{}: temperature step to {}°C flattened to {}°C",
+                context, step_temperature.0, temperature
+            ));
+        }
+    }
+
+    Phase {
+        name: step.name.clone(),
+        control,
+        start: None,
+        end,
+        transition,
+        transition_time,
+        restriction,
+        exit: ExitCondition::Any(conditions),
+    }
+}
+
+pub fn import_decent(decent: &DecentProfile) -> Result<ImportedProfile> {
+    if decent.steps.is_empty() {
+        bail!("Profile {:?} has no steps", decent.title);
+    }
+    let mut report = ImportReport::default();
+    let unknown = decent
+        .unknown
+        .iter()
+        .filter(|(key, _)| !IGNORED_FIELDS.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    report.warn_unknown("profile", &unknown);
+
+    if let Some(version) = decent.version {
+        if version.0 != 2.0 {
+            report.warn(format!(
+                "profile: version {} read as a version 2 profile",
+                version.0
+            ));
+        }
+    }
+    if let Some(beverage_type) = &decent.beverage_type {
+        if beverage_type != "espresso" {
+            report.warn(format!(
+                "profile: made for {:?}, imported as espresso",
+                beverage_type
+            ));
+        }
+    }
+    if decent.tank_temperature.0 > 0.0 {
+        report.warn(format!(
+            "profile: tank temperature of {}°C is not supported, dropped",
+            decent.tank_temperature.0
+        ));
+    }
+
+    // One brew temperature for the whole shot, the one the first step asks for.
+    let temperature = decent.steps[0].temperature.map(|temperature| temperature.0);
+    if decent
+        .steps
+        .iter()
+        .any(|step| step.sensor == Some(DecentSensor::Water))
+    {
+        report.approximate(
+            "profile: water (mix) temperatures are used as boiler temperatures".to_string(),
+        );
+    }
+
+    let phases = decent
+        .steps
+        .iter()
+        .enumerate()
+        .map(|(index, step)| {
+            let context = format!("step {}", index + 1);
+            import_step(step, &context, temperature, &mut report)
+        })
+        .collect();
+
+    // The DE1 stops on weight when it has a scale and on volume otherwise,
+    // volume is taken as weight assuming ~1g per ml.
+    let target_weight = if decent.target_weight.0 > 0.0 {
+        Some(decent.target_weight.0)
+    } else if decent.target_volume.0 > 0.0 {
+        report.approximate(format!(
+            "profile: target volume of {}ml used as a target weight of {}g",
+            decent.target_volume.0, decent.target_volume.0
+        ));
+        Some(decent.target_volume.0)
+    } else {
+        None
+    };
+
+    Ok(ImportedProfile {
+        profile: Profile {
+            name: decent.title.clone(),
+            phases,
+            temperature,
+            global_exit: target_weight.map(ExitCondition::Weight),
+            pressure_controller: None,
+        },
+        dose: None,
+        target_weight,
+        report,
+    })
+}
+
+pub fn import_decent_json(data: &[u8]) -> Result<ImportedProfile> {
+    let decent: DecentProfile = serde_json::from_slice(data)?;
+    import_decent(&decent)
+}
@@ -0,0 +1,342 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    functional::profile::{ControlType, ExitCondition, Phase, Profile, TransitionCurve},
+    profile_import::report::{ImportReport, ImportedProfile},
+};
+
+// Gaggiuino profile json, as exported by its web ui. Times are in milliseconds.
+// Anything we don't know about lands in `unknown` so it can be reported instead of silently lost.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GaggiuinoProfile {
+    pub name: String,
+    pub phases: Vec<GaggiuinoPhase>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub global_stop_conditions: Option<GaggiuinoStopConditions>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub water_temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipe: Option<GaggiuinoRecipe>,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GaggiuinoPhaseType {
+    Pressure,
+    Flow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GaggiuinoCurve {
+    Instant,
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GaggiuinoPhase {
+    #[serde(rename = "type")]
+    pub phase_type: GaggiuinoPhaseType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub target: GaggiuinoTarget,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restriction: Option<f32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip: bool,
+    #[serde(default)]
+    pub stop_conditions: GaggiuinoStopConditions,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GaggiuinoTarget {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<f32>,
+    pub end: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub curve: Option<GaggiuinoCurve>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GaggiuinoStopConditions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pressure_above: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pressure_below: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flow_above: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flow_below: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f32>,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GaggiuinoRecipe {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coffee_in: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coffee_out: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratio: Option<f32>,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, Value>,
+}
+
+fn from_curve(curve: GaggiuinoCurve) -> TransitionCurve {
+    match curve {
+        GaggiuinoCurve::Instant => TransitionCurve::Instant,
+        GaggiuinoCurve::Linear => TransitionCurve::Linear,
+        GaggiuinoCurve::EaseIn => TransitionCurve::EaseIn,
+        GaggiuinoCurve::EaseOut => TransitionCurve::EaseOut,
+        GaggiuinoCurve::EaseInOut => TransitionCurve::EaseInOut,
+    }
+}
+
+fn to_curve(curve: TransitionCurve) -> GaggiuinoCurve {
+    match curve {
+        TransitionCurve::Instant => GaggiuinoCurve::Instant,
+        TransitionCurve::Linear => GaggiuinoCurve::Linear,
+        TransitionCurve::EaseIn => GaggiuinoCurve::EaseIn,
+        TransitionCurve::EaseOut => GaggiuinoCurve::EaseOut,
+        TransitionCurve::EaseInOut => GaggiuinoCurve::EaseInOut,
+    }
+}
+
+fn from_stop_conditions(
+    stop_conditions: &GaggiuinoStopConditions,
+    context: &str,
+    report: &mut ImportReport,
+) -> ExitCondition {
+    let mut conditions = Vec::new();
+    if let Some(time) = stop_conditions.time {
+        conditions.push(ExitCondition::Time(Duration::from_millis(time)));
+    }
+    if let Some(pressure) = stop_conditions.pressure_above {
+        conditions.push(ExitCondition::PressureAbove(pressure));
+    }
+    if let Some(pressure) = stop_conditions.pressure_below {
+        conditions.push(ExitCondition::PressureBelow(pressure));
+    }
+    if let Some(flow) = stop_conditions.flow_above {
+        conditions.push(ExitCondition::FlowAbove(flow));
+    }
+    if let Some(flow) = stop_conditions.flow_below {
+        conditions.push(ExitCondition::FlowBelow(flow));
+    }
+    if let Some(weight) = stop_conditions.weight {
+        conditions.push(ExitCondition::Weight(weight));
+    }
+    report.warn_unknown(context, &stop_conditions.unknown);
+    ExitCondition::Any(conditions)
+}
+
+// Gaggiuino stops a phase on the first condition met, so only `Any` of plain conditions
+// has an exact equivalent. Nested `Any`s are flattened, `All` can't be represented.
+fn to_stop_conditions(
+    exit: &ExitCondition,
+    context: &str,
+    report: &mut ImportReport,
+) -> GaggiuinoStopConditions {
+    let mut stop_conditions = GaggiuinoStopConditions::default();
+    let mut pending = vec![exit];
+    while let Some(condition) = pending.pop() {
+        match condition {
+            ExitCondition::Any(conditions) => pending.extend(conditions.iter()),
+            ExitCondition::All(_) => report.warn(format!(
+                "{}: combined (all of) exit conditions are not supported, dropped",
+                context
+            )),
+            ExitCondition::Time(time) => {
+                let time = time.as_millis() as u64;
+                stop_conditions.time = Some(stop_conditions.time.map_or(time, |t| t.min(time)));
+            }
+            ExitCondition::PressureAbove(pressure) => {
+                stop_conditions.pressure_above = Some(
+                    stop_conditions
+                        .pressure_above
+                        .map_or(*pressure, |p| p.min(*pressure)),
+                );
+            }
+            ExitCondition::PressureBelow(pressure) => {
+                stop_conditions.pressure_below = Some(
+                    stop_conditions
+                        .pressure_below
+                        .map_or(*pressure, |p| p.max(*pressure)),
+                );
+            }
+            ExitCondition::FlowAbove(flow) => {
+                stop_conditions.flow_above =
+                    Some(stop_conditions.flow_above.map_or(*flow, |f| f.min(*flow)));
+            }
+            ExitCondition::FlowBelow(flow) => {
+                stop_conditions.flow_below =
+                    Some(stop_conditions.flow_below.map_or(*flow, |f| f.max(*flow)));
+            }
+            ExitCondition::ResistanceAbove(_) | ExitCondition::ResistanceBelow(_) => {
+                report.warn(format!(
+                    "{}: puck resistance exit conditions are not supported, dropped",
+                    context
+                ))
+            }
+            ExitCondition::Weight(weight) => {
+                stop_conditions.weight =
+                    Some(stop_conditions.weight.map_or(*weight, |w| w.min(*weight)));
+            }
+        }
+    }
+    stop_conditions
+}
+
+pub fn import_gaggiuino(gaggiuino: &GaggiuinoProfile) -> ImportedProfile {
+    let mut report = ImportReport::default();
+    report.warn_unknown("profile", &gaggiuino.unknown);
+
+    let mut phases = Vec::new();
+    for (index, phase) in gaggiuino.phases.iter().enumerate() {
+        let context = format!("phase {}", index + 1);
+        if phase.skip {
+            report.warn(format!("{}: marked as skipped, not imported", context));
+            continue;
+        }
+        report.warn_unknown(&context, &phase.unknown);
+        report.warn_unknown(&context, &phase.target.unknown);
+        phases.push(Phase {
+            name: phase.name.clone(),
+            control: match phase.phase_type {
+                GaggiuinoPhaseType::Pressure => ControlType::Pressure,
+                GaggiuinoPhaseType::Flow => ControlType::Flow,
+            },
+            start: phase.target.start,
+            end: phase.target.end,
+            transition: from_curve(phase.target.curve.unwrap_or(GaggiuinoCurve::Instant)),
+            transition_time: Duration::from_millis(phase.target.time.unwrap_or(0)),
+            restriction: phase.restriction.unwrap_or(0.0),
+            exit: from_stop_conditions(&phase.stop_conditions, &context, &mut report),
+        });
+    }
+
+    let global_exit = gaggiuino
+        .global_stop_conditions
+        .as_ref()
+        .map(|stop_conditions| from_stop_conditions(stop_conditions, "global", &mut report));
+
+    let recipe = gaggiuino.recipe.clone().unwrap_or_default();
+    report.warn_unknown("recipe", &recipe.unknown);
+    let target_weight = recipe
+        .coffee_out
+        .or(match (recipe.coffee_in, recipe.ratio) {
+            (Some(coffee_in), Some(ratio)) => Some(coffee_in * ratio),
+            _ => None,
+        });
+
+    ImportedProfile {
+        profile: Profile {
+            name: gaggiuino.name.clone(),
+            phases,
+            temperature: gaggiuino.water_temperature,
+            global_exit,
+            pressure_controller: None,
+        },
+        dose: recipe.coffee_in,
+        target_weight,
+        report,
+    }
+}
+
+pub fn export_gaggiuino(
+    profile: &Profile,
+    dose: Option<f32>,
+    target_weight: Option<f32>,
+) -> (GaggiuinoProfile, ImportReport) {
+    let mut report = ImportReport::default();
+    let phases = profile
+        .phases
+        .iter()
+        .enumerate()
+        .map(|(index, phase)| {
+            let context = format!("phase {}", index + 1);
+            GaggiuinoPhase {
+                phase_type: match phase.control {
+                    ControlType::Pressure => GaggiuinoPhaseType::Pressure,
+                    ControlType::Flow => GaggiuinoPhaseType::Flow,
+                },
+                name: phase.name.clone(),
+                target: GaggiuinoTarget {
+                    start: phase.start,
+                    end: phase.end,
+                    curve: Some(to_curve(phase.transition)),
+                    time: Some(phase.transition_time.as_millis() as u64),
+                    unknown: BTreeMap::new(),
+                },
+                restriction: if phase.restriction > 0.0 {
+                    Some(phase.restriction)
+                } else {
+                    None
+                },
+                skip: false,
+                stop_conditions: to_stop_conditions(&phase.exit, &context, &mut report),
+                unknown: BTreeMap::new(),
+            }
+        })
+        .collect();
+    if profile.pressure_controller.is_some() {
+        report.warn("profile: pressure controller not exported".to_string());
+    }
+    let global_stop_conditions = profile
+        .global_exit
+        .as_ref()
+        .map(|exit| to_stop_conditions(exit, "global", &mut report));
+    let recipe = if dose.is_some() || target_weight.is_some() {
+        Some(GaggiuinoRecipe {
+            coffee_in: dose,
+            coffee_out: target_weight,
+            ratio: None,
+            unknown: BTreeMap::new(),
+        })
+    } else {
+        None
+    };
+
+    (
+        GaggiuinoProfile {
+            name: profile.name.clone(),
+            phases,
+            global_stop_conditions,
+            water_temperature: profile.temperature,
+            recipe,
+            unknown: BTreeMap::new(),
+        },
+        report,
+    )
+}
+
+pub fn import_gaggiuino_json(data: &[u8]) -> Result<ImportedProfile> {
+    let gaggiuino: GaggiuinoProfile = serde_json::from_slice(data)?;
+    Ok(import_gaggiuino(&gaggiuino))
+}
@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::functional::profile::Profile;
+
+// What the importers hand back: the converted profile, the recipe values the source format
+// carries next to it, and everything that couldn't be converted exactly.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedProfile {
+    pub profile: Profile,
+    pub dose: Option<f32>,
+    pub target_weight: Option<f32>,
+    pub report: ImportReport,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport {
+    // Fields or values that were dropped.
+    pub warnings: Vec<String>,
+    // Fields that were converted, but not to an exact equivalent.
+    pub approximations: Vec<String>,
+}
+
+impl ImportReport {
+    pub fn warn(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    // Fields the importer has no mapping for, collected with `#[serde(flatten)]`.
+    pub fn warn_unknown(&mut self, context: &str, unknown: &BTreeMap<String, Value>) {
+        for key in unknown.keys() {
+            self.warn(format!("{}: unsupported field `{}` ignored", context, key));
+        }
+    }
+
+    pub fn approximate(&mut self, message: String) {
+        self.approximations.push(message);
+    }
+
+    pub fn is_exact(&self) -> bool {
+        self.warnings.is_empty() && self.approximations.is_empty()
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connectivity::{
+        commands::{push_command, MachineCommand},
+        transfer::{TRANSFER_BEGIN, TRANSFER_DATA, TRANSFER_END},
+    },
+    functional::presets::{profile_preset, PresetId, ShotPreset},
+    profile_import::{
+        decent::import_decent_json,
+        gaggiuino::import_gaggiuino_json,
+        report::{ImportReport, ImportedProfile},
+    },
+};
+
+// Profiles are bigger than a ble write, so the tablet sends them in chunks to the
+// `profile_upload` characteristic, framed as in `connectivity::transfer` with the format name
+// ("gaggiuino" or "decent") in the BEGIN chunk.
+// On END the whole file is queued as an import command for the main loop.
+
+// Matches the largest value nvs will store for the presets.
+const MAX_UPLOAD_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileFormat {
+    Gaggiuino,
+    Decent,
+}
+
+impl ProfileFormat {
+    fn from_name(name: &str) -> Result<ProfileFormat> {
+        match name.trim() {
+            "gaggiuino" => Ok(ProfileFormat::Gaggiuino),
+            "decent" => Ok(ProfileFormat::Decent),
+            other => bail!("Unknown profile format {:?}", other),
+        }
+    }
+}
+
+struct Upload {
+    format: ProfileFormat,
+    data: Vec<u8>,
+}
+
+static UPLOAD: OnceCell<Mutex<Option<Upload>>> = OnceCell::new();
+
+fn upload() -> &'static Mutex<Option<Upload>> {
+    UPLOAD.get_or_init(|| Mutex::new(None))
+}
+
+// Result published on the `profile_import` characteristic after every upload.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportResult {
+    pub id: Option<PresetId>,
+    pub error: Option<String>,
+    pub report: ImportReport,
+}
+
+pub fn handle_upload_chunk(chunk: &[u8]) -> Result<()> {
+    let (kind, payload) = chunk
+        .split_first()
+        .ok_or_else(|| anyhow!("Empty profile upload chunk"))?;
+    let mut upload = upload().lock().expect("Failed to acquire lock");
+    match *kind {
+        TRANSFER_BEGIN => {
+            let format = ProfileFormat::from_name(std::str::from_utf8(payload)?)?;
+            *upload = Some(Upload {
+                format,
+                data: Vec::new(),
+            });
+        }
+        TRANSFER_DATA => {
+            let current = upload
+                .as_mut()
+                .ok_or_else(|| anyhow!("Profile data received before the upload began"))?;
+            if current.data.len() + payload.len() > MAX_UPLOAD_SIZE {
+                *upload = None;
+                bail!("Profile upload is bigger than {} bytes", MAX_UPLOAD_SIZE);
+            }
+            current.data.extend_from_slice(payload);
+        }
+        TRANSFER_END => {
+            let current = upload
+                .take()
+                .ok_or_else(|| anyhow!("Profile upload ended before it began"))?;
+            push_command(MachineCommand::ImportProfile {
+                format: current.format,
+                data: current.data,
+            });
+        }
+        other => bail!("Unknown profile upload chunk type {:#04x}", other),
+    }
+    Ok(())
+}
+
+pub fn import_profile(format: ProfileFormat, data: &[u8]) -> Result<ImportedProfile> {
+    match format {
+        ProfileFormat::Gaggiuino => import_gaggiuino_json(data),
+        ProfileFormat::Decent => import_decent_json(data),
+    }
+}
+
+// Imported profiles are stored as custom presets named after the profile, so they can be
+// selected like any other preset.
+pub fn to_preset(imported: &ImportedProfile) -> (PresetId, ShotPreset) {
+    let preset = profile_preset(
+        imported.profile.clone(),
+        imported.dose,
+        imported.target_weight,
+    );
+    (PresetId::Custom(imported.profile.name.clone()), preset)
+}
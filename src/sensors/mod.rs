@@ -1,3 +1,4 @@
 pub mod flow;
+pub mod paddle;
 pub mod pressure;
 pub mod temperature;
@@ -0,0 +1,21 @@
+use anyhow::Result;
+use esp_idf_hal::adc::attenuation::DB_11;
+use esp_idf_hal::adc::oneshot::config::AdcChannelConfig;
+use esp_idf_hal::adc::oneshot::*;
+
+use crate::board::board::Board;
+
+const MAX_ADC_VALUE: f32 = 4095.0; // 12-bit ADC
+
+// Position of the paddle potentiometer as a fraction of the adc range. The ends of the track
+// don't reach 0.0 and 1.0, they are set with `potentiometer_min` and `potentiometer_max`.
+pub fn read_paddle(board: &mut Board) -> Result<f32> {
+    let mut adc = AdcDriver::new(&mut board.adc2)?;
+    let config = AdcChannelConfig {
+        attenuation: DB_11,
+        ..Default::default()
+    };
+    let mut adc_pin = AdcChannelDriver::new(&mut adc, &mut board.paddle_pin, &config)?;
+    // Raw counts: the calibrated `read` returns millivolts.
+    Ok(adc_pin.read_raw()? as f32 / MAX_ADC_VALUE)
+}
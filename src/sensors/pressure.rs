@@ -3,7 +3,7 @@ use esp_idf_hal::adc::attenuation::DB_11;
 use esp_idf_hal::adc::oneshot::config::AdcChannelConfig;
 use esp_idf_hal::adc::oneshot::*;
 
-use crate::board::board::Board;
+use crate::{board::board::Board, coffee_machine::water::record_pressure};
 
 fn convert_volt_to_pressure(adc_value: u16) -> f32 {
     let max_adc_value = 4095.0; // 12-bit ADC
@@ -32,6 +32,8 @@ pub fn read_pressure(board: &mut Board) -> Result<f32> {
     };
     let mut adc_pin = AdcChannelDriver::new(&mut adc, &mut board.pressure_pin, &config)?;
     let pressure = convert_volt_to_pressure(adc_pin.read()?);
+    // Every read feeds the pump model of the water tracker, whatever is running the pump.
+    record_pressure(pressure);
 
     // println!("ADC value: {}bar, {}", pressure, adc_pin.read()?);
     return Ok(pressure);
@@ -0,0 +1,238 @@
+use std::ffi::CString;
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_sys::{
+    esp, esp_partition_erase_range, esp_partition_find_first, esp_partition_read,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY, esp_partition_t,
+    esp_partition_type_t_ESP_PARTITION_TYPE_DATA, esp_partition_write,
+};
+
+// Append only log of records on a raw data partition, used as a ring: once the end of the
+// partition is reached writing starts again from the beginning, erasing the oldest records.
+//
+// Every record starts on a sector boundary with a header, followed by the payload:
+//   magic | sequence | payload length | payload crc | header crc
+// A record is written by erasing its sectors, writing the payload and writing the header
+// last. A power cut at any point leaves either no header or a header whose crcs don't match,
+// so a half written record is skipped, and records outside the erased sectors are never
+// touched.
+
+const SECTOR_SIZE: u32 = 4096;
+const HEADER_SIZE: u32 = 32;
+const RECORD_MAGIC: u32 = 0x5348_4f54;
+
+#[derive(Debug, Clone, Copy)]
+struct RecordLocation {
+    sequence: u32,
+    offset: u32,
+    length: u32,
+    crc: u32,
+}
+
+impl RecordLocation {
+    fn span(&self) -> u32 {
+        sectors_for(self.length) * SECTOR_SIZE
+    }
+
+    fn overlaps(&self, offset: u32, span: u32) -> bool {
+        self.offset < offset + span && offset < self.offset + self.span()
+    }
+}
+
+struct Partition(*const esp_partition_t);
+
+// The partition table lives in flash for the whole run, the pointer is only read.
+unsafe impl Send for Partition {}
+
+pub struct FlashRing {
+    partition: Partition,
+    size: u32,
+    // Sorted oldest first.
+    records: Vec<RecordLocation>,
+    next_offset: u32,
+    next_sequence: u32,
+}
+
+fn sectors_for(length: u32) -> u32 {
+    (HEADER_SIZE + length).div_ceil(SECTOR_SIZE)
+}
+
+pub const CRC32_INIT: u32 = 0xffff_ffff;
+
+// Feeds more data into a crc started at CRC32_INIT, the crc is the inverse of the result.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(CRC32_INIT, data)
+}
+
+fn encode_header(sequence: u32, length: u32, crc: u32) -> [u8; HEADER_SIZE as usize] {
+    let mut header = [0xffu8; HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&sequence.to_le_bytes());
+    header[8..12].copy_from_slice(&length.to_le_bytes());
+    header[12..16].copy_from_slice(&crc.to_le_bytes());
+    let header_crc = crc32(&header[0..16]);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+    header
+}
+
+fn decode_header(header: &[u8; HEADER_SIZE as usize], offset: u32) -> Option<RecordLocation> {
+    let word = |index: usize| u32::from_le_bytes(header[index..index + 4].try_into().unwrap());
+    if word(0) != RECORD_MAGIC || word(16) != crc32(&header[0..16]) {
+        return None;
+    }
+    Some(RecordLocation {
+        sequence: word(4),
+        offset,
+        length: word(8),
+        crc: word(12),
+    })
+}
+
+impl FlashRing {
+    pub fn open(label: &str) -> Result<FlashRing> {
+        let label = CString::new(label)?;
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                label.as_ptr(),
+            )
+        };
+        if partition.is_null() {
+            bail!("Partition {:?} not found", label);
+        }
+        let size = unsafe { (*partition).size } / SECTOR_SIZE * SECTOR_SIZE;
+        let mut ring = FlashRing {
+            partition: Partition(partition),
+            size,
+            records: Vec::new(),
+            next_offset: 0,
+            next_sequence: 0,
+        };
+        ring.scan()?;
+        Ok(ring)
+    }
+
+    fn read(&self, offset: u32, buffer: &mut [u8]) -> Result<()> {
+        esp!(unsafe {
+            esp_partition_read(
+                self.partition.0,
+                offset as usize,
+                buffer.as_mut_ptr() as *mut _,
+                buffer.len(),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn write(&self, offset: u32, data: &[u8]) -> Result<()> {
+        esp!(unsafe {
+            esp_partition_write(
+                self.partition.0,
+                offset as usize,
+                data.as_ptr() as *const _,
+                data.len(),
+            )
+        })?;
+        Ok(())
+    }
+
+    // Finds every intact header. Where two records claim the same sectors the newer one
+    // was written over the older one, so the older is dropped.
+    fn scan(&mut self) -> Result<()> {
+        let mut found = Vec::new();
+        let mut header = [0u8; HEADER_SIZE as usize];
+        for offset in (0..self.size).step_by(SECTOR_SIZE as usize) {
+            self.read(offset, &mut header)?;
+            if let Some(location) = decode_header(&header, offset) {
+                if location.offset + location.span() <= self.size {
+                    found.push(location);
+                }
+            }
+        }
+        found.sort_by(|a, b| b.sequence.cmp(&a.sequence));
+        let mut records: Vec<RecordLocation> = Vec::new();
+        for location in found {
+            if !records
+                .iter()
+                .any(|newer| newer.overlaps(location.offset, location.span()))
+            {
+                records.push(location);
+            }
+        }
+        records.reverse();
+        if let Some(newest) = records.last() {
+            self.next_offset = newest.offset + newest.span();
+            self.next_sequence = newest.sequence.wrapping_add(1);
+        }
+        self.records = records;
+        Ok(())
+    }
+
+    // Sequence numbers of the stored records, oldest first.
+    pub fn sequences(&self) -> Vec<u32> {
+        self.records.iter().map(|record| record.sequence).collect()
+    }
+
+    pub fn append(&mut self, data: &[u8]) -> Result<u32> {
+        let length = data.len() as u32;
+        let span = sectors_for(length) * SECTOR_SIZE;
+        // Keep room for at least two records so a new one never evicts everything.
+        if span > self.size / 2 {
+            bail!("Record of {} bytes doesn't fit in the ring", length);
+        }
+        let mut offset = self.next_offset;
+        if offset + span > self.size {
+            offset = 0;
+        }
+
+        self.records.retain(|record| !record.overlaps(offset, span));
+        // Erasing takes a few hundred ms per sector, records are only appended from the main
+        // task so the control task never waits on it.
+        esp!(unsafe {
+            esp_partition_erase_range(self.partition.0, offset as usize, span as usize)
+        })?;
+        let crc = crc32(data);
+        self.write(offset + HEADER_SIZE, data)?;
+        let sequence = self.next_sequence;
+        self.write(offset, &encode_header(sequence, length, crc))?;
+
+        self.records.push(RecordLocation {
+            sequence,
+            offset,
+            length,
+            crc,
+        });
+        self.next_offset = offset + span;
+        self.next_sequence = sequence.wrapping_add(1);
+        Ok(sequence)
+    }
+
+    pub fn read_record(&self, sequence: u32) -> Result<Vec<u8>> {
+        let record = self
+            .records
+            .iter()
+            .find(|record| record.sequence == sequence)
+            .ok_or_else(|| anyhow!("No record {}", sequence))?;
+        let mut data = vec![0u8; record.length as usize];
+        self.read(record.offset + HEADER_SIZE, &mut data)?;
+        if crc32(&data) != record.crc {
+            bail!("Record {} is corrupted", sequence);
+        }
+        Ok(data)
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use once_cell::sync::OnceCell;
+use serde::{de::DeserializeOwned, Serialize};
+
+// Small key/value store on the default nvs partition, values are stored as json blobs.
+// Keys are limited to 15 characters by nvs.
+//
+// A flash write can take long enough to hold up a control cycle, so the control task
+// queues its writes and the publisher writes them on the main task. Reads see a queued
+// value before it reaches the flash.
+
+static NVS: OnceCell<Mutex<EspNvs<NvsDefault>>> = OnceCell::new();
+// Latest queued value per key, None removes the key.
+static QUEUED: Mutex<Vec<(String, Option<Vec<u8>>)>> = Mutex::new(Vec::new());
+const NVS_NAMESPACE: &str = "anitta";
+// Blobs can span several nvs pages, presets with profiles need more than one.
+const MAX_VALUE_SIZE: usize = 8192;
+
+pub fn init_nvs() -> Result<()> {
+    let partition = EspDefaultNvsPartition::take()?;
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    NVS.set(Mutex::new(nvs)).ok();
+    Ok(())
+}
+
+fn queued_value(key: &str) -> Option<Option<Vec<u8>>> {
+    QUEUED
+        .lock()
+        .expect("Failed to acquire lock")
+        .iter()
+        .find(|(queued_key, _)| queued_key == key)
+        .map(|(_, data)| data.clone())
+}
+
+pub fn read_json<T: DeserializeOwned>(key: &str) -> Option<T> {
+    if let Some(data) = queued_value(key) {
+        return data.and_then(|data| serde_json::from_slice(&data).ok());
+    }
+    let nvs = NVS.get()?.lock().expect("Failed to acquire lock");
+    let mut buffer = vec![0u8; MAX_VALUE_SIZE];
+    match nvs.get_raw(key, &mut buffer) {
+        Ok(Some(data)) => match serde_json::from_slice(data) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::error!("Failed to deserialize nvs key {}: {:?}", key, e);
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            log::error!("Failed to read nvs key {}: {:?}", key, e);
+            None
+        }
+    }
+}
+
+fn to_blob<T: Serialize>(key: &str, value: &T) -> Result<Vec<u8>> {
+    let data = serde_json::to_vec(value)?;
+    if data.len() > MAX_VALUE_SIZE {
+        anyhow::bail!(
+            "Value for nvs key {} is too big ({} bytes)",
+            key,
+            data.len()
+        );
+    }
+    Ok(data)
+}
+
+pub fn write_json<T: Serialize>(key: &str, value: &T) -> Result<()> {
+    let data = to_blob(key, value)?;
+    match NVS.get() {
+        Some(nvs) => {
+            nvs.lock()
+                .expect("Failed to acquire lock")
+                .set_raw(key, &data)?;
+            Ok(())
+        }
+        None => anyhow::bail!("NVS is not initialized"),
+    }
+}
+
+fn queue(key: &str, data: Option<Vec<u8>>) {
+    let mut queued = QUEUED.lock().expect("Failed to acquire lock");
+    match queued.iter_mut().find(|(queued_key, _)| queued_key == key) {
+        Some(entry) => entry.1 = data,
+        None => queued.push((key.to_string(), data)),
+    }
+}
+
+// For the control task, written by the next `write_queued`.
+pub fn queue_json<T: Serialize>(key: &str, value: &T) -> Result<()> {
+    queue(key, Some(to_blob(key, value)?));
+    Ok(())
+}
+
+pub fn queue_remove(key: &str) {
+    queue(key, None);
+}
+
+// Called by the publisher on the main task. A key stays queued until its value is on the
+// flash, so reads never fall back on the old one in between, and a failed write is retried
+// by the next call.
+pub fn write_queued() {
+    let queued = QUEUED.lock().expect("Failed to acquire lock").clone();
+    let Some(nvs) = NVS.get() else {
+        return;
+    };
+    let mut written = Vec::new();
+    for entry in &queued {
+        let (key, data) = entry;
+        let result = {
+            let mut nvs = nvs.lock().expect("Failed to acquire lock");
+            match data {
+                Some(data) => nvs.set_raw(key, data).map(|_| ()),
+                None => nvs.remove(key).map(|_| ()),
+            }
+        };
+        match result {
+            Ok(()) => written.push(entry),
+            Err(e) => log::error!("Failed to write nvs key {}, retrying: {:?}", key, e),
+        }
+    }
+    // Failed writes and values queued again while writing are kept for the next call.
+    QUEUED
+        .lock()
+        .expect("Failed to acquire lock")
+        .retain(|entry| !written.contains(&entry));
+}
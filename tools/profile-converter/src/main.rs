@@ -0,0 +1,555 @@
+use std::{fs, path::Path, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+// The profile model and the importers are the firmware's own files, so what this tool
+// accepts is exactly what the machine accepts over ble. The tests also run the pump
+// protection's duty tracking and the fit of shot replays.
+#[path = "../../../src/actuators"]
+#[allow(dead_code)]
+mod actuators {
+    pub mod controller_config;
+    pub mod pump_duty;
+}
+
+#[path = "../../../src/functional"]
+#[allow(dead_code)]
+mod functional {
+    pub mod profile;
+    pub mod replay_fit;
+    pub mod shot_series;
+}
+
+#[path = "../../../src/profile_import"]
+#[allow(dead_code)]
+mod profile_import {
+    pub mod decent;
+    pub mod gaggiuino;
+    pub mod report;
+}
+
+use functional::profile::Profile;
+use profile_import::{
+    decent::import_decent_json,
+    gaggiuino::{export_gaggiuino, import_gaggiuino, import_gaggiuino_json, GaggiuinoProfile},
+    report::{ImportReport, ImportedProfile},
+};
+
+const USAGE: &str = "usage:
+  profile-converter import <profile.json>     print the firmware profile (gaggiuino or decent)
+  profile-converter export <profile.json>     print the gaggiuino profile
+  profile-converter check [--update] <file or dir>...
+      round trip every gaggiuino profile and compare every decent import with its
+      <name>.expected.json, --update rewrites the expected files";
+
+const EXPECTED_SUFFIX: &str = ".expected.json";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Gaggiuino,
+    Decent,
+}
+
+// Gaggiuino profiles have phases, DE1 profiles have steps.
+fn detect_format(data: &[u8]) -> Result<Format> {
+    let value: serde_json::Value = serde_json::from_slice(data)?;
+    if value.get("phases").is_some() {
+        Ok(Format::Gaggiuino)
+    } else if value.get("steps").is_some() {
+        Ok(Format::Decent)
+    } else {
+        bail!("Not a gaggiuino or decent profile")
+    }
+}
+
+fn import_any(data: &[u8]) -> Result<ImportedProfile> {
+    match detect_format(data)? {
+        Format::Gaggiuino => import_gaggiuino_json(data),
+        Format::Decent => import_decent_json(data),
+    }
+}
+
+fn print_report(report: &ImportReport) {
+    for warning in &report.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    for approximation in &report.approximations {
+        eprintln!("approximated: {}", approximation);
+    }
+}
+
+fn import(path: &Path) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let imported = import_any(&data)?;
+    print_report(&imported.report);
+    println!("{}", serde_json::to_string_pretty(&imported)?);
+    Ok(())
+}
+
+fn export(path: &Path) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    // Accepts either a bare profile or the output of `import`.
+    let (profile, dose, target_weight) = match serde_json::from_slice::<Profile>(&data) {
+        Ok(profile) => (profile, None, None),
+        Err(_) => {
+            let imported: serde_json::Value = serde_json::from_slice(&data)?;
+            let profile: Profile = serde_json::from_value(imported["profile"].clone())
+                .context("Expected a profile or an imported profile")?;
+            let dose = imported["dose"].as_f64().map(|dose| dose as f32);
+            let target_weight = imported["target_weight"]
+                .as_f64()
+                .map(|weight| weight as f32);
+            (profile, dose, target_weight)
+        }
+    };
+    let (gaggiuino, report) = export_gaggiuino(&profile, dose, target_weight);
+    print_report(&report);
+    println!("{}", serde_json::to_string_pretty(&gaggiuino)?);
+    Ok(())
+}
+
+// Gaggiuino -> firmware -> gaggiuino -> firmware has to give back the same profile and recipe,
+// and a second export has to be identical to the first.
+fn round_trip(path: &Path) -> Result<ImportReport> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let imported = import_gaggiuino_json(&data)?;
+    let (exported, export_report) =
+        export_gaggiuino(&imported.profile, imported.dose, imported.target_weight);
+    if !export_report.is_exact() {
+        print_report(&export_report);
+        bail!("export of the imported profile is not exact");
+    }
+    let exported_json = serde_json::to_vec(&exported)?;
+    let reparsed: GaggiuinoProfile = serde_json::from_slice(&exported_json)?;
+    let reimported: ImportedProfile = import_gaggiuino(&reparsed);
+    if !reimported.report.is_exact() {
+        print_report(&reimported.report);
+        bail!("re-import of the exported profile is not exact");
+    }
+    if reimported.profile != imported.profile {
+        bail!(
+            "profile changed on the round trip:\n{:#?}\n{:#?}",
+            imported.profile,
+            reimported.profile
+        );
+    }
+    if reimported.dose != imported.dose || reimported.target_weight != imported.target_weight {
+        bail!("recipe changed on the round trip");
+    }
+    let (exported_again, _) = export_gaggiuino(
+        &reimported.profile,
+        reimported.dose,
+        reimported.target_weight,
+    );
+    if exported_again != exported {
+        bail!("second export differs from the first");
+    }
+    Ok(imported.report)
+}
+
+// DE1 profiles can't be exported back, so their import is compared with a reviewed copy.
+fn compare_expected(path: &Path, update: bool) -> Result<ImportReport> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let imported = import_decent_json(&data)?;
+    let actual = serde_json::to_value(&imported)?;
+    let expected_path = expected_path(path);
+    if update {
+        fs::write(
+            &expected_path,
+            serde_json::to_string_pretty(&actual)? + "\n",
+        )?;
+    } else {
+        let expected = fs::read(&expected_path)
+            .with_context(|| format!("Failed to read {}", expected_path.display()))?;
+        let expected: serde_json::Value = serde_json::from_slice(&expected)?;
+        if actual != expected {
+            bail!(
+                "import differs from {}:\n{}",
+                expected_path.display(),
+                serde_json::to_string_pretty(&actual)?
+            );
+        }
+    }
+    Ok(imported.report)
+}
+
+fn expected_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}{}", stem, EXPECTED_SUFFIX))
+}
+
+fn collect_files(paths: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        let path = PathBuf::from(path);
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(&path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|entry| entry.extension().is_some_and(|ext| ext == "json"))
+                .filter(|entry| !entry.to_string_lossy().ends_with(EXPECTED_SUFFIX))
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn check(paths: &[String], update: bool) -> Result<()> {
+    let mut failures = 0;
+    for file in collect_files(paths)? {
+        let result = fs::read(&file)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| detect_format(&data))
+            .and_then(|format| match format {
+                Format::Gaggiuino => round_trip(&file),
+                Format::Decent => compare_expected(&file, update),
+            });
+        match result {
+            Ok(report) => {
+                println!(
+                    "ok   {} ({} warnings, {} approximations)",
+                    file.display(),
+                    report.warnings.len(),
+                    report.approximations.len()
+                );
+                print_report(&report);
+            }
+            Err(e) => {
+                failures += 1;
+                println!("FAIL {}: {:?}", file.display(), e);
+            }
+        }
+    }
+    if failures > 0 {
+        bail!("{} profiles failed the check", failures);
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.split_first() {
+        Some((command, [path])) if command == "import" => import(Path::new(path)),
+        Some((command, [path])) if command == "export" => export(Path::new(path)),
+        Some((command, [flag, paths @ ..])) if command == "check" && flag == "--update" => {
+            check(paths, true)
+        }
+        Some((command, paths)) if command == "check" && !paths.is_empty() => check(paths, false),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use actuators::pump_duty::{PumpFault, PumpProtection, PumpProtectionConfig, MIN_OFF_GAP};
+    use functional::profile::{ControlType, ExitCondition, ProfileEngine, ProfileInput};
+    use functional::replay_fit::{replay_profile, ReplayConfig, ReplaySource};
+    use functional::shot_series::ShotSeries;
+
+    fn fixture_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = fixture_path(name);
+        fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e))
+    }
+
+    fn input(seconds: f32, pressure: f32, weight: f32) -> ProfileInput {
+        ProfileInput {
+            shot_time: Duration::from_secs_f32(seconds),
+            pressure,
+            flow: 2.0,
+            weight,
+            resistance: None,
+        }
+    }
+
+    #[test]
+    fn engine_walks_the_default_profile() {
+        let imported = import_gaggiuino_json(&fixture("gaggiuino/default.json")).unwrap();
+        let mut engine = ProfileEngine::new(imported.profile);
+
+        let target = engine.step(&input(0.0, 0.0, 0.0)).unwrap();
+        assert_eq!((target.phase, target.control), (0, ControlType::Flow));
+        assert_eq!((target.target, target.restriction), (4.0, 3.0));
+
+        // Preinfusion exits on the pressure before its 10s.
+        let target = engine.step(&input(2.0, 3.5, 0.0)).unwrap();
+        assert_eq!((target.phase, target.target), (1, 0.0));
+
+        // The soak runs its 5s from the moment it was entered.
+        assert_eq!(engine.step(&input(6.9, 0.5, 0.0)).unwrap().phase, 1);
+        let target = engine.step(&input(7.0, 0.5, 0.0)).unwrap();
+        assert_eq!((target.phase, target.target), (2, 2.0));
+
+        // Halfway through the ease in out ramp from 2 to 9 bar.
+        let target = engine.step(&input(9.0, 5.0, 0.0)).unwrap();
+        assert_eq!(target.phase, 2);
+        assert!((target.target - 5.5).abs() < 0.01, "{}", target.target);
+
+        let target = engine.step(&input(11.0, 9.0, 10.0)).unwrap();
+        assert_eq!((target.phase, target.target), (3, 9.0));
+        assert_eq!(engine.current_phase(), Some(3));
+
+        // The last phase has no exit, the global weight ends the shot.
+        assert!(engine.step(&input(20.0, 9.0, 36.0)).is_none());
+        assert!(engine.is_finished());
+        assert_eq!(engine.current_phase(), None);
+    }
+
+    #[test]
+    fn engine_stops_on_the_global_time() {
+        let imported = import_gaggiuino_json(&fixture("gaggiuino/default.json")).unwrap();
+        let mut engine = ProfileEngine::new(imported.profile);
+        assert!(engine.step(&input(0.0, 0.0, 0.0)).is_some());
+        assert!(engine.step(&input(60.0, 9.0, 20.0)).is_none());
+        assert!(engine.is_finished());
+    }
+
+    #[test]
+    fn engine_skips_phases_that_exit_at_once() {
+        let imported = import_gaggiuino_json(&fixture("gaggiuino/default.json")).unwrap();
+        let mut engine = ProfileEngine::new(imported.profile);
+        // Over the preinfusion's weight and pressure on the first cycle.
+        let target = engine.step(&input(0.0, 4.0, 1.5)).unwrap();
+        assert_eq!(target.phase, 1);
+    }
+
+    #[test]
+    fn gaggiuino_profiles_round_trip() {
+        for name in ["default.json", "londinium.json", "unsupported-fields.json"] {
+            if let Err(e) = round_trip(&fixture_path(&format!("gaggiuino/{}", name))) {
+                panic!("{}: {:?}", name, e);
+            }
+        }
+    }
+
+    #[test]
+    fn gaggiuino_round_trip_keeps_the_recipe() {
+        let imported = import_gaggiuino_json(&fixture("gaggiuino/default.json")).unwrap();
+        assert_eq!(imported.dose, Some(18.0));
+        assert_eq!(imported.target_weight, Some(36.0));
+        assert_eq!(imported.profile.temperature, Some(93.0));
+        assert!(imported.report.is_exact());
+
+        let (exported, report) =
+            export_gaggiuino(&imported.profile, imported.dose, imported.target_weight);
+        assert!(report.is_exact(), "{:?}", report);
+        let exported = serde_json::to_value(&exported).unwrap();
+        assert_eq!(exported["recipe"]["coffeeIn"], 18.0);
+        assert_eq!(exported["recipe"]["coffeeOut"], 36.0);
+        assert_eq!(exported["phases"].as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn gaggiuino_import_reports_what_it_drops() {
+        let report = round_trip(&fixture_path("gaggiuino/unsupported-fields.json")).unwrap();
+        assert!(report
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("unsupported field `id`")));
+        assert!(report
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("skipped")));
+    }
+
+    #[test]
+    fn decent_imports_match_the_expected_files() {
+        for name in ["adaptive.json", "blooming.json", "londinium.json"] {
+            if let Err(e) = compare_expected(&fixture_path(&format!("decent/{}", name)), false) {
+                panic!("{}: {:?}", name, e);
+            }
+        }
+    }
+
+    #[test]
+    fn decent_step_exits_become_phase_exits() {
+        let imported = import_decent_json(&fixture("decent/blooming.json")).unwrap();
+        let preinfusion = &imported.profile.phases[0];
+        assert_eq!(preinfusion.control, ControlType::Flow);
+        assert_eq!(
+            preinfusion.exit,
+            ExitCondition::Any(vec![
+                ExitCondition::Time(Duration::from_secs(23)),
+                ExitCondition::PressureAbove(4.0),
+            ])
+        );
+    }
+
+    #[test]
+    fn decent_out_of_range_step_times_are_reported() {
+        let mut profile: serde_json::Value =
+            serde_json::from_slice(&fixture("decent/blooming.json")).unwrap();
+        profile["steps"][0]["seconds"] = "inf".into();
+        profile["steps"][1]["seconds"] = 1e30.into();
+        let imported = import_decent_json(&serde_json::to_vec(&profile).unwrap()).unwrap();
+        let out_of_range = imported
+            .report
+            .warnings
+            .iter()
+            .filter(|warning| warning.contains("out of range"))
+            .count();
+        assert_eq!(out_of_range, 2, "{:?}", imported.report);
+    }
+
+    // Off every other cycle at 25Hz, like the pressure controller hunting at its target.
+    // Returns when the protection stops the pump or after `seconds`.
+    fn toggle_pump(
+        protection: &mut PumpProtection,
+        now: &mut Instant,
+        seconds: u64,
+    ) -> Option<PumpFault> {
+        for index in 0..seconds * 25 {
+            let requested = if index % 2 == 0 { 100 } else { 0 };
+            if let Err(fault) = protection.check(requested, *now) {
+                return Some(fault);
+            }
+            *now += Duration::from_millis(40);
+        }
+        None
+    }
+
+    #[test]
+    fn a_hunting_pump_is_one_continuous_run() {
+        let config = PumpProtectionConfig {
+            max_duty: 1.0,
+            ..PumpProtectionConfig::default()
+        };
+        let mut protection = PumpProtection::new(config.clone());
+        let start = Instant::now();
+        let mut now = start;
+        let fault = toggle_pump(&mut protection, &mut now, 300);
+        assert!(matches!(fault, Some(PumpFault::MaxContinuousRun { .. })));
+        assert!(now - start >= config.max_continuous_run);
+        assert!(now - start < config.max_continuous_run + Duration::from_secs(1));
+        assert_eq!(protection.run_count(), 1);
+        assert!(protection.last_fault(now).is_some());
+        assert!(protection.last_fault(now + config.cool_down).is_none());
+    }
+
+    #[test]
+    fn pump_runs_are_bounded_by_the_off_gap() {
+        let mut protection = PumpProtection::new(PumpProtectionConfig {
+            max_duty: 1.0,
+            ..PumpProtectionConfig::default()
+        });
+        let mut now = Instant::now();
+        for _ in 0..200 {
+            assert!(toggle_pump(&mut protection, &mut now, 1).is_none());
+            protection.check(0, now).unwrap();
+            now += MIN_OFF_GAP;
+            protection.check(0, now).unwrap();
+        }
+        let window = PumpProtectionConfig::default().duty_window;
+        let max_runs = (window.as_secs() / MIN_OFF_GAP.as_secs()) as usize + 1;
+        assert!(protection.run_count() <= max_runs);
+    }
+
+    #[test]
+    fn pump_duty_is_limited_over_the_window() {
+        let mut protection = PumpProtection::new(PumpProtectionConfig::default());
+        let mut now = Instant::now();
+        let mut fault = None;
+        // 60s on, 10s off: over the 50% duty well before the window is full.
+        for _ in 0..20 {
+            match protection.check(100, now) {
+                Ok(_) => {}
+                Err(e) => {
+                    fault = Some(e);
+                    break;
+                }
+            }
+            now += Duration::from_secs(60);
+            protection.check(0, now).unwrap();
+            now += Duration::from_secs(10);
+        }
+        assert!(matches!(fault, Some(PumpFault::DutyCycleExceeded { .. })));
+    }
+
+    // A shot sampled every 250ms with the pressure (and pump flow) from `pressure`.
+    fn recorded_shot(seconds: f32, pressure: impl Fn(f32) -> f32) -> ShotSeries {
+        let mut samples = ShotSeries::default();
+        for index in 0..=(seconds * 4.0) as u32 {
+            let time = index as f32 / 4.0;
+            samples.time_ms.push(index * 250);
+            samples.pressure.push(pressure(time));
+            samples.pump_flow.push(pressure(time) / 4.0);
+        }
+        samples
+    }
+
+    fn replay_source(samples: &ShotSeries, stopped_on_weight: bool) -> ReplaySource<'_> {
+        ReplaySource {
+            samples,
+            temperature: 93.0,
+            stopped_on_weight,
+            final_weight: 36.5,
+            duration: Duration::from_secs(20),
+        }
+    }
+
+    #[test]
+    fn replay_fits_a_ramp_and_a_plateau() {
+        let samples = recorded_shot(20.0, |time| (time * 1.5).min(9.0));
+        let config = ReplayConfig {
+            smoothing: 0,
+            ..Default::default()
+        };
+        let profile = replay_profile(&replay_source(&samples, true), "ramp", &config).unwrap();
+        assert_eq!(profile.phases.len(), 2);
+        let (ramp, plateau) = (&profile.phases[0], &profile.phases[1]);
+        assert_eq!(ramp.start, Some(0.0));
+        assert_eq!(ramp.end, 9.0);
+        assert_eq!(ramp.transition_time, Duration::from_secs(6));
+        assert_eq!(ramp.exit, ExitCondition::Time(Duration::from_secs(6)));
+        assert_eq!(plateau.start, None);
+        assert_eq!(plateau.end, 9.0);
+        assert_eq!(plateau.exit, ExitCondition::Any(Vec::new()));
+        assert_eq!(profile.temperature, Some(93.0));
+    }
+
+    #[test]
+    fn replay_keeps_to_the_phase_limit() {
+        // A sawtooth between 6 and 9 bar that no handful of segments fits.
+        let samples = recorded_shot(20.0, |time| 6.0 + 3.0 * (time % 2.0) / 2.0);
+        let config = ReplayConfig {
+            max_phases: 3,
+            smoothing: 0,
+            ..Default::default()
+        };
+        let profile = replay_profile(&replay_source(&samples, true), "saw", &config).unwrap();
+        assert_eq!(profile.phases.len(), 3);
+
+        let config = ReplayConfig {
+            max_phases: 0,
+            ..Default::default()
+        };
+        assert!(replay_profile(&replay_source(&samples, true), "none", &config).is_err());
+    }
+
+    #[test]
+    fn replay_stops_like_the_recorded_shot() {
+        let samples = recorded_shot(20.0, |time| (time * 1.5).min(9.0));
+        let config = ReplayConfig::default();
+        let on_weight = replay_profile(&replay_source(&samples, true), "weight", &config).unwrap();
+        assert_eq!(on_weight.global_exit, Some(ExitCondition::Weight(36.5)));
+        let on_time = replay_profile(&replay_source(&samples, false), "time", &config).unwrap();
+        assert_eq!(
+            on_time.global_exit,
+            Some(ExitCondition::Time(Duration::from_secs(20)))
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use std::{fs, path::Path, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+// Converts shot records saved from the `shot_export` characteristic (format "record") with
+// the firmware's own export code, and runs the firmware's shot analysis on them. The tests
+// also run the drip lag model of the predictive stop.
+#[path = "../../../src/functional"]
+#[allow(dead_code)]
+mod functional {
+    pub mod drip_compensation;
+    pub mod shot_analysis;
+    pub mod shot_export;
+    pub mod shot_series;
+}
+
+use functional::shot_analysis::{analyse_record, AnalysisConfig};
+use functional::shot_export::{export_shot, ExportFormat, ExportedShot};
+use functional::shot_series::ShotSeries;
+
+const USAGE: &str = "usage:
+  shot-converter visualizer <record.json>   print the visualizer.coffee shot file
+  shot-converter csv <record.json>          print the samples as csv
+  shot-converter analyse <record.json>      print the anomalies the firmware detects
+  shot-converter check [--update] <record.json>...
+      compare both exports and the analysis with <name>.visualizer.json, <name>.csv and
+      <name>.events.json next to the record, --update rewrites them";
+
+fn read_record(path: &Path) -> Result<serde_json::Value> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+// The columns of a `ShotRecord` the exports are written from, the firmware borrows them from
+// the record it reads from flash.
+#[derive(Deserialize)]
+struct SavedShot {
+    #[serde(default)]
+    id: u32,
+    start_time: u64,
+    profile: Option<String>,
+    dose: Option<f32>,
+    target_weight: Option<f32>,
+    final_weight: f32,
+    duration: Duration,
+    peak_pressure: f32,
+    stop_reason: serde_json::Value,
+    samples: ShotSeries,
+}
+
+fn export(record: &serde_json::Value, format: ExportFormat) -> Result<Vec<u8>> {
+    let saved = SavedShot::deserialize(record).context("Not a shot record")?;
+    let shot = ExportedShot {
+        record,
+        id: saved.id,
+        start_time: saved.start_time,
+        profile: saved.profile.as_deref(),
+        dose: saved.dose,
+        target_weight: saved.target_weight,
+        final_weight: saved.final_weight,
+        duration: saved.duration,
+        peak_pressure: saved.peak_pressure,
+        stop_reason: saved.stop_reason.clone(),
+        samples: &saved.samples,
+    };
+    let mut data = Vec::new();
+    export_shot(&shot, format, &mut data)?;
+    Ok(data)
+}
+
+fn print_export(path: &Path, format: ExportFormat) -> Result<()> {
+    let data = export(&read_record(path)?, format)?;
+    println!("{}", String::from_utf8(data)?);
+    Ok(())
+}
+
+fn analyse(path: &Path) -> Result<Vec<u8>> {
+    let events = analyse_record(&read_record(path)?, &AnalysisConfig::default())?;
+    Ok(serde_json::to_vec_pretty(&events)?)
+}
+
+fn expected_path(path: &Path, extension: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}", stem, extension))
+}
+
+fn check_output(path: &Path, actual: Vec<u8>, extension: &str, update: bool) -> Result<()> {
+    let expected_path = expected_path(path, extension);
+    if update {
+        fs::write(&expected_path, &actual)?;
+        return Ok(());
+    }
+    let expected = fs::read(&expected_path)
+        .with_context(|| format!("Failed to read {}", expected_path.display()))?;
+    if actual != expected {
+        bail!("output differs from {}", expected_path.display());
+    }
+    Ok(())
+}
+
+fn check(paths: &[String], update: bool) -> Result<()> {
+    let mut failures = 0;
+    for path in paths {
+        let path = Path::new(path);
+        let result = read_record(path).and_then(|record| {
+            let visualizer = export(&record, ExportFormat::Visualizer)?;
+            check_output(path, visualizer, "visualizer.json", update)?;
+            let csv = export(&record, ExportFormat::Csv)?;
+            check_output(path, csv, "csv", update)?;
+            check_output(path, analyse(path)?, "events.json", update)
+        });
+        match result {
+            Ok(()) => println!("ok   {}", path.display()),
+            Err(e) => {
+                failures += 1;
+                println!("FAIL {}: {:?}", path.display(), e);
+            }
+        }
+    }
+    if failures > 0 {
+        bail!("{} shots failed the check", failures);
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.split_first() {
+        Some((command, [path])) if command == "visualizer" => {
+            print_export(Path::new(path), ExportFormat::Visualizer)
+        }
+        Some((command, [path])) if command == "csv" => {
+            print_export(Path::new(path), ExportFormat::Csv)
+        }
+        Some((command, [path])) if command == "analyse" => {
+            println!("{}", String::from_utf8(analyse(Path::new(path))?)?);
+            Ok(())
+        }
+        Some((command, [flag, paths @ ..])) if command == "check" && flag == "--update" => {
+            check(paths, true)
+        }
+        Some((command, paths)) if command == "check" && !paths.is_empty() => check(paths, false),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use functional::drip_compensation::{DripCompensation, LearnResult, StopPoint};
+    use functional::shot_analysis::{
+        analyse_samples, AnalysisSample, ShotEvent, ShotEventKind, ShotOutcome,
+    };
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    fn events(name: &str) -> Vec<ShotEvent> {
+        let record = read_record(&fixture(name)).unwrap();
+        analyse_record(&record, &AnalysisConfig::default()).unwrap()
+    }
+
+    fn kinds(events: &[ShotEvent]) -> Vec<ShotEventKind> {
+        events.iter().map(|event| event.kind).collect()
+    }
+
+    #[test]
+    fn detects_the_pressure_drop_of_a_channel() {
+        let events = events("channeling.json");
+        assert_eq!(kinds(&events), [ShotEventKind::PressureDrop]);
+        assert_eq!(events[0].time_ms, 18750);
+    }
+
+    #[test]
+    fn modelled_weight_is_not_a_first_drip() {
+        // The pump model passes the first drip weight within 3s, the meters only see drips
+        // after 5s.
+        let events = events("shot.json");
+        assert!(!kinds(&events).contains(&ShotEventKind::FastFirstDrip));
+    }
+
+    #[test]
+    fn measured_early_drips_are_a_fast_first_drip() {
+        let mut record = read_record(&fixture("shot.json")).unwrap();
+        let weight = record["samples"]["weight"].clone();
+        record["samples"]["measured_weight"] = weight;
+        let events = analyse_record(&record, &AnalysisConfig::default()).unwrap();
+        assert!(kinds(&events).contains(&ShotEventKind::FastFirstDrip));
+    }
+
+    fn sample(time_ms: u32, pressure: f32, flow: f32) -> AnalysisSample {
+        AnalysisSample {
+            time_ms,
+            pressure,
+            flow,
+            weight: 0.0,
+            measured_weight: None,
+        }
+    }
+
+    #[test]
+    fn steady_pressure_with_a_rising_flow_is_a_flow_rise() {
+        let samples: Vec<AnalysisSample> = (0..=16)
+            .map(|index| sample(10_000 + index * 250, 9.0, 1.5 + index as f32 * 0.1))
+            .collect();
+        let outcome = ShotOutcome {
+            duration: Duration::from_secs(20),
+            final_weight: 36.0,
+            target_weight: Some(36.0),
+        };
+        let events = analyse_samples(&samples, &outcome, &AnalysisConfig::default());
+        assert_eq!(kinds(&events), [ShotEventKind::FlowRise]);
+    }
+
+    #[test]
+    fn outcomes_flag_gushers_and_chokers() {
+        let config = AnalysisConfig::default();
+        let gusher = ShotOutcome {
+            duration: Duration::from_secs(12),
+            final_weight: 36.0,
+            target_weight: Some(36.0),
+        };
+        assert_eq!(
+            kinds(&analyse_samples(&[], &gusher, &config)),
+            [ShotEventKind::Gusher]
+        );
+        let choker = ShotOutcome {
+            duration: Duration::from_secs(50),
+            final_weight: 20.0,
+            target_weight: Some(36.0),
+        };
+        assert_eq!(
+            kinds(&analyse_samples(&[], &choker, &config)),
+            [ShotEventKind::Choker]
+        );
+    }
+
+    #[test]
+    fn fixtures_match_their_expected_outputs() {
+        let paths = ["shot.json", "channeling.json"]
+            .map(|name| fixture(name).to_string_lossy().into_owned());
+        check(&paths, false).unwrap();
+    }
+
+    fn stop(measured_weight: Option<f32>, measured_flow: f32) -> StopPoint {
+        StopPoint {
+            time: Instant::now(),
+            measured_weight,
+            measured_flow,
+            target_weight: 36.0,
+        }
+    }
+
+    #[test]
+    fn pump_model_stops_keep_the_drip_lag() {
+        // The pump model counted 36g of water at the stop, the cup settled at 31g.
+        let mut compensation = DripCompensation::default();
+        for _ in 0..10 {
+            assert_eq!(
+                compensation.learn(&stop(None, 2.0), 31.0),
+                LearnResult::NoMeasuredWeight
+            );
+        }
+        assert_eq!(compensation, DripCompensation::default());
+    }
+
+    #[test]
+    fn measured_stops_move_the_drip_lag() {
+        // 34g in the cup at 2g/s, settled at 37g: a lag of 1.5s.
+        let mut compensation = DripCompensation::default();
+        for _ in 0..30 {
+            assert_eq!(
+                compensation.learn(&stop(Some(34.0), 2.0), 37.0),
+                LearnResult::Learned
+            );
+        }
+        assert!((compensation.drip_lag - 1.5).abs() < 0.01);
+        assert_eq!(compensation.shots_learned, 30);
+        assert_eq!(
+            compensation.learn(&stop(Some(34.0), 0.1), 37.0),
+            LearnResult::LowFlow
+        );
+    }
+}